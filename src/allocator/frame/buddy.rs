@@ -1,8 +1,12 @@
 use core::{marker::PhantomData, mem};
 
+use super::run_order;
 use crate::{
-    Arch, BumpAllocator, FrameAllocator, FrameCount, FrameUsage, PhysicalAddress, VirtualAddress,
+    Arch, BumpAllocator, FrameAllocator, FrameCount, FrameUsage, PageMapper, PhysRange,
+    PhysicalAddress, VirtualAddress, MAX_ORDER,
 };
+#[cfg(feature = "trace")]
+use crate::{RmmTrace, TraceEvent};
 
 #[repr(transparent)]
 struct BuddyUsage(u8);
@@ -81,6 +85,8 @@ impl<A: Arch> BuddyEntry<A> {
 
 pub struct BuddyAllocator<A> {
     table_virt: VirtualAddress,
+    #[cfg(feature = "trace")]
+    trace: Option<&'static dyn RmmTrace>,
     phantom: PhantomData<A>,
 }
 
@@ -99,6 +105,8 @@ impl<A: Arch> BuddyAllocator<A> {
 
             let allocator = Self {
                 table_virt,
+                #[cfg(feature = "trace")]
+                trace: None,
                 phantom: PhantomData,
             };
 
@@ -177,6 +185,86 @@ impl<A: Arch> BuddyAllocator<A> {
             Some(allocator)
         }
     }
+
+    /// Report every allocation and free to `trace`
+    #[cfg(feature = "trace")]
+    pub fn with_trace(mut self, trace: &'static dyn RmmTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace(&self, event: TraceEvent) {
+        if let Some(trace) = self.trace {
+            trace.event(event);
+        }
+    }
+
+    /// Move the frames of this allocator that are mapped by base pages of `mapper` to the lowest
+    /// free frames, which merges free frames into larger runs at the top. Returns the number of
+    /// frames moved.
+    ///
+    /// `refcount` returns the number of mappings that refer to a frame, and only frames with a
+    /// single reference are moved. This visits every page of `mapper`, which must not be the
+    /// physmap, and the tables of `mapper` must come from another allocator. The mapper is
+    /// flushed for each moved page.
+    pub unsafe fn compact<F: FrameAllocator>(
+        &mut self,
+        mapper: &mut PageMapper<A, F>,
+        mut refcount: impl FnMut(PhysicalAddress) -> usize,
+    ) -> usize {
+        unsafe {
+            let mut moved = 0;
+            for (virt, phys, _, size) in mapper.iter() {
+                if size != A::PAGE_SIZE
+                    || self.is_allocated(phys) != Some(true)
+                    || refcount(phys) != 1
+                {
+                    continue;
+                }
+                let Some(new_phys) = self.allocate_one() else {
+                    break;
+                };
+                if new_phys > phys {
+                    self.free_one(new_phys);
+                    continue;
+                }
+                match mapper.migrate(virt, new_phys) {
+                    Some((old_phys, flush)) => {
+                        flush.flush();
+                        self.free_one(old_phys);
+                        moved += 1;
+                    }
+                    None => self.free_one(new_phys),
+                }
+            }
+            moved
+        }
+    }
+
+    /// Count the free runs by order into `out`, which shows whether large contiguous allocations
+    /// can still succeed
+    pub unsafe fn fragmentation_histogram(&self, out: &mut [usize; MAX_ORDER]) {
+        unsafe {
+            *out = [0; MAX_ORDER];
+            for i in 0..Self::BUDDY_ENTRIES {
+                let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+                let entry = A::read::<BuddyEntry<A>>(virt);
+                let mut run = 0;
+                for page in entry.skip..entry.pages() {
+                    if entry.usage(page).is_some_and(|usage| usage.0 == 0) {
+                        run += 1;
+                    } else if run > 0 {
+                        out[run_order(run)] += 1;
+                        run = 0;
+                    }
+                }
+                if run > 0 {
+                    out[run_order(run)] += 1;
+                }
+            }
+        }
+    }
 }
 
 impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
@@ -232,7 +320,13 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
                     // Write updated entry
                     A::write(virt, entry);
 
-                    return Some(entry.base.add(free_page << A::PAGE_SHIFT));
+                    let base = entry.base.add(free_page << A::PAGE_SHIFT);
+                    #[cfg(feature = "trace")]
+                    self.trace(TraceEvent::Allocate {
+                        base,
+                        count: free_count,
+                    });
+                    return Some(base);
                 }
             }
 
@@ -240,6 +334,61 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
         }
     }
 
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            if self.table_virt.data() == 0
+                || count.data() == 0
+                || phys.data() & A::PAGE_OFFSET_MASK != 0
+            {
+                return None;
+            }
+
+            let size = count.data() * A::PAGE_SIZE;
+            for entry_i in 0..Self::BUDDY_ENTRIES {
+                let virt = self
+                    .table_virt
+                    .add(entry_i * mem::size_of::<BuddyEntry<A>>());
+                let mut entry = A::read::<BuddyEntry<A>>(virt);
+                if phys < { entry.base } || phys.add(size) > entry.base.add(entry.size) {
+                    continue;
+                }
+
+                let start_page = (phys.data() - { entry.base }.data()) >> A::PAGE_SHIFT;
+                let pages = start_page..start_page + count.data();
+                if !pages
+                    .clone()
+                    .all(|page| entry.usage(page).is_some_and(|usage| usage.0 == 0))
+                {
+                    return None;
+                }
+                for page in pages {
+                    entry.set_usage(page, BuddyUsage(1))?;
+                    let page_virt = A::phys_to_virt(entry.base.add(page << A::PAGE_SHIFT));
+                    A::write_bytes(page_virt, 0, A::PAGE_SIZE);
+                }
+
+                // Update skip if necessary
+                if entry.skip == start_page {
+                    entry.skip = start_page + count.data();
+                }
+                entry.used += count.data();
+                A::write(virt, entry);
+                #[cfg(feature = "trace")]
+                self.trace(TraceEvent::Allocate {
+                    base: phys,
+                    count: count.data(),
+                });
+                return Some(phys);
+            }
+
+            None
+        }
+    }
+
     unsafe fn free(&mut self, base: PhysicalAddress, count: FrameCount) {
         unsafe {
             if self.table_virt.data() == 0 {
@@ -281,6 +430,11 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
                     // Write updated entry
                     A::write(virt, entry);
 
+                    #[cfg(feature = "trace")]
+                    self.trace(TraceEvent::Free {
+                        base,
+                        count: count.data(),
+                    });
                     return;
                 }
             }
@@ -300,4 +454,233 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
             FrameUsage::new(FrameCount::new(used), FrameCount::new(total))
         }
     }
+
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        unsafe {
+            if self.table_virt.data() == 0 {
+                return None;
+            }
+
+            for i in 0..Self::BUDDY_ENTRIES {
+                let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+                let entry = A::read::<BuddyEntry<A>>(virt);
+                if phys >= { entry.base } && phys < entry.base.add(entry.size) {
+                    let page = (phys.data() - { entry.base }.data()) >> A::PAGE_SHIFT;
+                    return entry.usage(page).map(|usage| usage.0 > 0);
+                }
+            }
+            None
+        }
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        unsafe {
+            let mut free = 0;
+            for i in 0..Self::BUDDY_ENTRIES {
+                let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+                let entry = A::read::<BuddyEntry<A>>(virt);
+                for page in entry.skip..entry.pages() {
+                    if entry.base.add((page + 1) << A::PAGE_SHIFT) > limit {
+                        break;
+                    }
+                    if entry.usage(page).is_some_and(|usage| usage.0 == 0) {
+                        free += 1;
+                    }
+                }
+            }
+            FrameCount::new(free)
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        A::PAGE_SIZE
+    }
+
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        unsafe {
+            if self.table_virt.data() == 0 {
+                return None;
+            }
+
+            let mut range: Option<(PhysicalAddress, PhysicalAddress)> = None;
+            for i in 0..Self::BUDDY_ENTRIES {
+                let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+                let entry = A::read::<BuddyEntry<A>>(virt);
+                if entry.size == 0 {
+                    continue;
+                }
+                let (start, end) = ({ entry.base }, entry.base.add(entry.size));
+                range =
+                    Some(range.map_or((start, end), |(min, max)| (min.min(start), max.max(end))));
+            }
+            range.map(|(start, end)| PhysRange::new(start, end.data() - start.data()))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use crate::{
+        Arch, EmulateArch, FrameAllocator, FrameCount, PageFlags, PageMapper, PhysicalAddress,
+        TableKind, TinyAllocator, VirtualAddress, MAX_ORDER, MEGABYTE,
+    };
+
+    type A = EmulateArch;
+
+    #[test]
+    fn free_below() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let limit = PhysicalAddress::new(32 * MEGABYTE);
+            let low = allocator.free_below(limit).data();
+            let all = allocator
+                .free_below(PhysicalAddress::new(usize::MAX))
+                .data();
+            assert_eq!(all, allocator.usage().free().data());
+            // The upper half of emulated memory is above the limit and still entirely free
+            assert_eq!(all - low, 32 * MEGABYTE / A::PAGE_SIZE);
+
+            let phys = allocator.allocate_one().unwrap();
+            assert!(phys < limit);
+            assert_eq!(allocator.free_below(limit).data(), low - 1);
+            allocator.free_one(phys);
+            assert_eq!(allocator.free_below(limit).data(), low);
+        }
+    }
+
+    #[test]
+    fn allocate_at() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let free = allocator.usage().free().data();
+            let phys = PhysicalAddress::new(40 * MEGABYTE);
+
+            assert_eq!(allocator.allocate_at(phys, FrameCount::new(4)), Some(phys));
+            assert_eq!(allocator.usage().free().data(), free - 4);
+            assert_eq!(A::read::<usize>(A::phys_to_virt(phys)), 0);
+
+            // Overlapping frames are already used
+            let overlap = phys.add(3 * A::PAGE_SIZE);
+            assert_eq!(allocator.allocate_at(overlap, FrameCount::new(2)), None);
+            assert_eq!(allocator.allocate_at(phys, FrameCount::new(1)), None);
+            assert_eq!(allocator.usage().free().data(), free - 4);
+
+            // Outside of memory, or straddling its end
+            let end = PhysicalAddress::new(64 * MEGABYTE);
+            assert_eq!(allocator.allocate_at(end, FrameCount::new(1)), None);
+            let straddle = PhysicalAddress::new(64 * MEGABYTE - A::PAGE_SIZE);
+            assert_eq!(allocator.allocate_at(straddle, FrameCount::new(2)), None);
+
+            // Not page aligned, or empty
+            let unaligned = PhysicalAddress::new(44 * MEGABYTE + 0x10);
+            assert_eq!(allocator.allocate_at(unaligned, FrameCount::new(1)), None);
+            let empty = PhysicalAddress::new(44 * MEGABYTE);
+            assert_eq!(allocator.allocate_at(empty, FrameCount::new(0)), None);
+            assert_eq!(allocator.usage().free().data(), free - 4);
+
+            allocator.free(phys, FrameCount::new(4));
+            assert_eq!(
+                allocator.allocate_at(overlap, FrameCount::new(2)),
+                Some(overlap)
+            );
+            assert_eq!(allocator.usage().free().data(), free - 2);
+        }
+    }
+
+    #[test]
+    fn is_allocated() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let phys = PhysicalAddress::new(48 * MEGABYTE);
+            assert_eq!(allocator.is_allocated(phys), Some(false));
+
+            assert_eq!(allocator.allocate_at(phys, FrameCount::new(1)), Some(phys));
+            assert_eq!(allocator.is_allocated(phys), Some(true));
+            assert_eq!(allocator.is_allocated(phys.add(0xFFF)), Some(true));
+            assert_eq!(allocator.is_allocated(phys.add(A::PAGE_SIZE)), Some(false));
+
+            allocator.free_one(phys);
+            assert_eq!(allocator.is_allocated(phys), Some(false));
+            // The emulated machine ends at 64 MiB
+            let end = PhysicalAddress::new(64 * MEGABYTE);
+            assert_eq!(allocator.is_allocated(end), None);
+        }
+    }
+
+    #[test]
+    fn compact() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut tables = TinyAllocator::<A>::empty();
+            tables.add(
+                allocator.allocate(FrameCount::new(16)).unwrap(),
+                FrameCount::new(16),
+            );
+            let mut mapper = PageMapper::<A, _>::new_empty(TableKind::User, &mut tables).unwrap();
+            let virt = VirtualAddress::new(MEGABYTE);
+            let flags = PageFlags::new().write(true);
+
+            // Map every other frame of a block, and keep the block apart from the free frames
+            // after it
+            let base = allocator.allocate(FrameCount::new(32)).unwrap();
+            let _guard = allocator.allocate_one().unwrap();
+            for i in 0..16 {
+                let phys = base.add((2 * i + 1) * A::PAGE_SIZE);
+                mapper
+                    .map_phys(virt.add(i * A::PAGE_SIZE), phys, flags)
+                    .unwrap()
+                    .ignore();
+                A::write(A::phys_to_virt(phys), i);
+                allocator.free_one(base.add(2 * i * A::PAGE_SIZE));
+            }
+            let mut before = [0; MAX_ORDER];
+            allocator.fragmentation_histogram(&mut before);
+
+            // A frame that is also mapped elsewhere stays in place
+            let shared = base.add(31 * A::PAGE_SIZE);
+            assert_eq!(
+                allocator.compact(&mut mapper, |phys| 1 + usize::from(phys == shared)),
+                15
+            );
+            assert_eq!(
+                mapper.translate(virt.add(15 * A::PAGE_SIZE)).unwrap().0,
+                shared
+            );
+            assert_eq!(allocator.compact(&mut mapper, |_| 1), 1);
+            let mut after = [0; MAX_ORDER];
+            allocator.fragmentation_histogram(&mut after);
+            assert_eq!(after[0], before[0] - 16);
+            assert_eq!(after[4], before[4] + 1);
+            for i in 0..16 {
+                let (phys, _) = mapper.translate(virt.add(i * A::PAGE_SIZE)).unwrap();
+                assert_eq!(phys, base.add(i * A::PAGE_SIZE));
+                assert_eq!(A::read::<usize>(A::phys_to_virt(phys)), i);
+            }
+
+            // Already compacted
+            assert_eq!(allocator.compact(&mut mapper, |_| 1), 0);
+        }
+    }
+
+    #[test]
+    fn fragmentation_histogram() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut before = [0; MAX_ORDER];
+            allocator.fragmentation_histogram(&mut before);
+            assert!(before[MAX_ORDER - 1] > 0);
+
+            // Free frames 1 and 3 to 5 of a block in the middle of used frames
+            let base = allocator.allocate(FrameCount::new(8)).unwrap();
+            // Keeps the freed frames apart from the free frames after the block
+            let _guard = allocator.allocate_one().unwrap();
+            allocator.free(base.add(A::PAGE_SIZE), FrameCount::new(1));
+            allocator.free(base.add(3 * A::PAGE_SIZE), FrameCount::new(3));
+            let mut after = [0; MAX_ORDER];
+            allocator.fragmentation_histogram(&mut after);
+            assert_eq!(after[0], before[0] + 1);
+            assert_eq!(after[1], before[1] + 1);
+            assert_eq!(after[2..], before[2..]);
+        }
+    }
 }
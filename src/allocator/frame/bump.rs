@@ -1,6 +1,18 @@
 use core::marker::PhantomData;
 
-use crate::{Arch, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysicalAddress};
+use crate::{
+    area_frame_count, Arch, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysRange,
+    PhysicalAddress,
+};
+
+/// Magic number at the start of a memory map exported with [`BumpAllocator::export_map`]
+pub const EXPORT_MAP_MAGIC: [u8; 4] = *b"RMMM";
+/// Version of the export format, incremented whenever the layout changes
+pub const EXPORT_MAP_VERSION: u32 = 1;
+/// Size of the export header: magic, version, area count and record size, as 32-bit values
+pub const EXPORT_MAP_HEADER_SIZE: usize = 16;
+/// Size of each area record: base, size and used bytes, as 64-bit values
+pub const EXPORT_MAP_RECORD_SIZE: usize = 24;
 
 #[derive(Debug)]
 pub struct BumpAllocator<A> {
@@ -38,6 +50,54 @@ impl<A: Arch> BumpAllocator<A> {
             .first()
             .map_or(PhysicalAddress::new(0), |a| a.base.add(off))
     }
+    // Frames in `areas`, skipping the first `offset` bytes of the first area
+    fn frames((areas, offset): (&[MemoryArea], usize)) -> usize {
+        areas
+            .iter()
+            .enumerate()
+            .map(|(i, area)| {
+                let skip = if i == 0 { offset } else { 0 };
+                area_frame_count::<A>(&MemoryArea {
+                    base: area.base.add(skip),
+                    size: area.size - skip,
+                })
+            })
+            .sum()
+    }
+    /// Write the memory map in a compact binary format for external tools: a header followed by
+    /// one record per area, with all values little-endian. Used bytes of an area are the bytes
+    /// before the allocation cursor.
+    ///
+    /// Returns the size of the whole map. Nothing is written if it does not fit in `out`.
+    pub fn export_map(&self, out: &mut [u8]) -> usize {
+        let areas = self.areas();
+        let len = EXPORT_MAP_HEADER_SIZE + areas.len() * EXPORT_MAP_RECORD_SIZE;
+        if out.len() < len {
+            return len;
+        }
+
+        out[0..4].copy_from_slice(&EXPORT_MAP_MAGIC);
+        out[4..8].copy_from_slice(&EXPORT_MAP_VERSION.to_le_bytes());
+        out[8..12].copy_from_slice(&(areas.len() as u32).to_le_bytes());
+        out[12..16].copy_from_slice(&(EXPORT_MAP_RECORD_SIZE as u32).to_le_bytes());
+
+        // The current areas are a suffix of the original ones
+        let (cur_areas, cur_offset) = self.cur_areas;
+        let cur = areas.len() - cur_areas.len();
+        for (i, area) in areas.iter().enumerate() {
+            let used = match i.cmp(&cur) {
+                core::cmp::Ordering::Less => area.size,
+                core::cmp::Ordering::Equal => cur_offset,
+                core::cmp::Ordering::Greater => 0,
+            };
+            let record = &mut out[EXPORT_MAP_HEADER_SIZE + i * EXPORT_MAP_RECORD_SIZE..]
+                [..EXPORT_MAP_RECORD_SIZE];
+            record[0..8].copy_from_slice(&(area.base.data() as u64).to_le_bytes());
+            record[8..16].copy_from_slice(&(area.size as u64).to_le_bytes());
+            record[16..24].copy_from_slice(&(used as u64).to_le_bytes());
+        }
+        len
+    }
     pub fn offset(&self) -> usize {
         (unsafe { self.usage().total().data() - self.usage().free().data() }) * A::PAGE_SIZE
     }
@@ -64,16 +124,253 @@ impl<A: Arch> FrameAllocator for BumpAllocator<A> {
         }
     }
 
+    /// Only succeeds if `phys` is the next free frame, and the whole range fits in its area
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            let area = self.cur_areas.0.first()?;
+            if phys != self.abs_offset()
+                || area.size - self.cur_areas.1 < count.data() * A::PAGE_SIZE
+            {
+                return None;
+            }
+            self.allocate(count)
+        }
+    }
+
     unsafe fn free(&mut self, _address: PhysicalAddress, _count: FrameCount) {
         unimplemented!("BumpAllocator::free not implemented");
     }
 
     unsafe fn usage(&self) -> FrameUsage {
-        let total = self.orig_areas.0.iter().map(|a| a.size).sum::<usize>() - self.orig_areas.1;
-        let free = self.cur_areas.0.iter().map(|a| a.size).sum::<usize>() - self.cur_areas.1;
-        FrameUsage::new(
-            FrameCount::new((total - free) / A::PAGE_SIZE),
-            FrameCount::new(total / A::PAGE_SIZE),
-        )
+        let total = Self::frames(self.orig_areas);
+        let free = Self::frames(self.cur_areas);
+        FrameUsage::new(FrameCount::new(total - free), FrameCount::new(total))
+    }
+
+    /// Frames before the allocation cursor are allocated, those after it are free
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        let areas = self.areas();
+        let i = areas
+            .iter()
+            .position(|area| phys >= area.base && phys < area.base.add(area.size))?;
+        let (cur_areas, cur_offset) = self.cur_areas;
+        let cur = areas.len() - cur_areas.len();
+        Some(i < cur || (i == cur && phys < areas[i].base.add(cur_offset)))
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        let (areas, offset) = self.cur_areas;
+        let free = areas
+            .iter()
+            .enumerate()
+            .map(|(i, area)| {
+                // Only the first area is partially used
+                let start = area.base.add(if i == 0 { offset } else { 0 });
+                let end = area.base.add(area.size).min(limit);
+                area_frame_count::<A>(&MemoryArea {
+                    base: start,
+                    size: end.data().saturating_sub(start.data()),
+                })
+            })
+            .sum::<usize>();
+        FrameCount::new(free)
+    }
+
+    fn page_size(&self) -> usize {
+        A::PAGE_SIZE
+    }
+
+    /// Spans all areas, except for those skipped entirely by the initial offset
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        let start = self.areas().iter().map(|area| area.base).min()?;
+        let end = self
+            .areas()
+            .iter()
+            .map(|area| area.base.add(area.size))
+            .max()?;
+        Some(PhysRange::new(start, end.data() - start.data()))
+    }
+}
+
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::{
+        BumpAllocator, EXPORT_MAP_HEADER_SIZE, EXPORT_MAP_MAGIC, EXPORT_MAP_RECORD_SIZE,
+        EXPORT_MAP_VERSION,
+    };
+    use crate::{
+        Arch, FrameAllocator, MemoryArea, PhysRange, PhysicalAddress, X8664Arch, MEGABYTE,
+    };
+
+    type A = X8664Arch;
+
+    static AREAS: [MemoryArea; 3] = [
+        MemoryArea {
+            base: PhysicalAddress::new(0x1000),
+            size: 16 * A::PAGE_SIZE,
+        },
+        MemoryArea {
+            base: PhysicalAddress::new(MEGABYTE),
+            size: 32 * A::PAGE_SIZE,
+        },
+        MemoryArea {
+            base: PhysicalAddress::new(16 * MEGABYTE),
+            size: MEGABYTE,
+        },
+    ];
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn allocate_at() {
+        use crate::{EmulateArch, FrameCount};
+
+        unsafe {
+            let areas = EmulateArch::init();
+            let mut allocator = BumpAllocator::<EmulateArch>::new(areas, 0);
+            let page = |i| areas[0].base.add(i * EmulateArch::PAGE_SIZE);
+
+            assert_eq!(
+                allocator.allocate_at(page(0), FrameCount::new(1)),
+                Some(page(0))
+            );
+            // Already used, and not at the cursor
+            assert_eq!(allocator.allocate_at(page(0), FrameCount::new(1)), None);
+            assert_eq!(allocator.allocate_at(page(2), FrameCount::new(1)), None);
+            assert_eq!(
+                allocator.allocate_at(page(1), FrameCount::new(1)),
+                Some(page(1))
+            );
+
+            // Past the end of the current area
+            let count = areas[0].size / EmulateArch::PAGE_SIZE - 1;
+            assert_eq!(allocator.allocate_at(page(2), FrameCount::new(count)), None);
+            assert_eq!(allocator.usage().used().data(), 2);
+        }
+    }
+
+    #[test]
+    fn export_map() {
+        // Skips the first area entirely, and 4 pages of the second
+        let allocator = BumpAllocator::<A>::new(&AREAS, 20 * A::PAGE_SIZE);
+        let mut out = [0xFF; 128];
+        let len = allocator.export_map(&mut out[..16]);
+        assert_eq!(len, EXPORT_MAP_HEADER_SIZE + 2 * EXPORT_MAP_RECORD_SIZE);
+        assert_eq!(out[0], 0xFF);
+        assert_eq!(allocator.export_map(&mut out), len);
+        assert_eq!(out[len], 0xFF);
+
+        let u32_at = |i: usize| u32::from_le_bytes(out[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(out[i..i + 8].try_into().unwrap()) as usize;
+        assert_eq!(out[0..4], EXPORT_MAP_MAGIC);
+        assert_eq!(u32_at(4), EXPORT_MAP_VERSION);
+        assert_eq!(u32_at(8), 2);
+        assert_eq!(u32_at(12) as usize, EXPORT_MAP_RECORD_SIZE);
+
+        let areas = allocator.areas();
+        let (_, offset) = allocator.free_areas();
+        for (i, area) in areas.iter().enumerate() {
+            let record = EXPORT_MAP_HEADER_SIZE + i * EXPORT_MAP_RECORD_SIZE;
+            assert_eq!(u64_at(record), area.base.data());
+            assert_eq!(u64_at(record + 8), area.size);
+            let used = if i == 0 { offset } else { 0 };
+            assert_eq!(u64_at(record + 16), used);
+        }
+        assert_eq!(u64_at(EXPORT_MAP_HEADER_SIZE), MEGABYTE);
+        assert_eq!(u64_at(EXPORT_MAP_HEADER_SIZE + 16), 4 * A::PAGE_SIZE);
+    }
+
+    #[test]
+    fn is_allocated() {
+        let allocator = BumpAllocator::<A>::new(&AREAS, 20 * A::PAGE_SIZE);
+        let is_allocated =
+            |phys: usize| unsafe { allocator.is_allocated(PhysicalAddress::new(phys)) };
+        assert_eq!(is_allocated(MEGABYTE), Some(true));
+        assert_eq!(is_allocated(MEGABYTE + 3 * A::PAGE_SIZE), Some(true));
+        assert_eq!(is_allocated(MEGABYTE + 4 * A::PAGE_SIZE), Some(false));
+        assert_eq!(is_allocated(16 * MEGABYTE), Some(false));
+        // Between areas, and in the first area that was skipped entirely
+        assert_eq!(is_allocated(2 * MEGABYTE), None);
+        assert_eq!(is_allocated(0x1000), None);
+    }
+
+    #[test]
+    fn managed_range() {
+        let allocator = BumpAllocator::<A>::new(&AREAS, 20 * A::PAGE_SIZE);
+        assert_eq!(allocator.page_size(), A::PAGE_SIZE);
+        let range = unsafe { allocator.managed_range() }.unwrap();
+        assert_eq!(
+            range,
+            PhysRange::new(PhysicalAddress::new(MEGABYTE), 16 * MEGABYTE)
+        );
+        assert!(range.contains(PhysicalAddress::new(2 * MEGABYTE)));
+        assert!(!range.contains(PhysicalAddress::new(17 * MEGABYTE)));
+
+        let allocator = BumpAllocator::<A>::new(&AREAS, 0);
+        assert_eq!(
+            unsafe { allocator.managed_range() }.map(|range| range.base),
+            Some(PhysicalAddress::new(0x1000))
+        );
+    }
+
+    #[test]
+    fn area_frame_count() {
+        static UNEVEN: [MemoryArea; 3] = [
+            MemoryArea {
+                base: PhysicalAddress::new(0x1000),
+                size: 3 * A::PAGE_SIZE + 0x800,
+            },
+            MemoryArea {
+                base: PhysicalAddress::new(MEGABYTE),
+                size: 0x800,
+            },
+            MemoryArea {
+                base: PhysicalAddress::new(2 * MEGABYTE),
+                size: 5 * A::PAGE_SIZE + 0xFFF,
+            },
+        ];
+
+        // 3.5 pages, half a page and a byte short of 6 pages hold only 8 whole frames
+        let frames = UNEVEN.iter().map(crate::area_frame_count::<A>);
+        assert_eq!(frames.sum::<usize>(), 8);
+        let allocator = BumpAllocator::<A>::new(&UNEVEN, 0);
+        let usage = unsafe { allocator.usage() };
+        assert_eq!(usage.total().data(), 8);
+        assert_eq!(usage.used().data(), 0);
+        let limit = PhysicalAddress::new(usize::MAX);
+        assert_eq!(unsafe { allocator.free_below(limit) }.data(), 8);
+
+        let allocator = BumpAllocator::<A>::new(&UNEVEN, A::PAGE_SIZE);
+        let usage = unsafe { allocator.usage() };
+        assert_eq!(usage.total().data(), 7);
+        assert_eq!(unsafe { allocator.free_below(limit) }.data(), 7);
+    }
+
+    #[test]
+    fn free_below() {
+        let allocator = BumpAllocator::<A>::new(&AREAS, 4 * A::PAGE_SIZE);
+        let free_below =
+            |limit: usize| unsafe { allocator.free_below(PhysicalAddress::new(limit)).data() };
+
+        assert_eq!(free_below(0), 0);
+        assert_eq!(free_below(0x1000 + 4 * A::PAGE_SIZE), 0);
+        assert_eq!(free_below(0x1000 + 5 * A::PAGE_SIZE), 1);
+        assert_eq!(free_below(MEGABYTE), 12);
+        assert_eq!(free_below(MEGABYTE + 8 * A::PAGE_SIZE), 12 + 8);
+        assert_eq!(free_below(16 * MEGABYTE), 12 + 32);
+        assert_eq!(
+            free_below(usize::MAX),
+            unsafe { allocator.usage() }.free().data()
+        );
+
+        // Areas used up entirely are skipped
+        let allocator = BumpAllocator::<A>::new(&AREAS, 20 * A::PAGE_SIZE);
+        assert_eq!(
+            unsafe { allocator.free_below(PhysicalAddress::new(16 * MEGABYTE)) }.data(),
+            28
+        );
     }
 }
@@ -0,0 +1,213 @@
+use core::marker::PhantomData;
+
+use crate::{Arch, FrameAllocator, FrameCount, FrameUsage, PhysRange, PhysicalAddress};
+
+/// Maximum number of single frames a [`CleanPoolAllocator`] can hold
+pub const CLEAN_POOL_MAX: usize = 32;
+
+/// Frame allocator wrapper that keeps a pool of freed single frames, so that they can be zeroed
+/// ahead of time with [`Self::zero_pending`], such as when idle, instead of on allocation.
+///
+/// Frames in the pool count as free in [`FrameAllocator::usage`], whether they are zeroed yet or
+/// not, and a frame counts as used as soon as it is handed out, even by
+/// [`Self::allocate_uninit`]. The free frames that still need zeroing are reported by
+/// [`FrameAllocator::pending_zero`].
+pub struct CleanPoolAllocator<A, F> {
+    inner: F,
+    // Frames held by the pool, the first `clean` of them are zeroed
+    frames: [PhysicalAddress; CLEAN_POOL_MAX],
+    len: usize,
+    clean: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch, F: FrameAllocator> CleanPoolAllocator<A, F> {
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            frames: [PhysicalAddress::new(0); CLEAN_POOL_MAX],
+            len: 0,
+            clean: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Move up to `count` zeroed frames from the inner allocator into the pool, returning how
+    /// many were moved
+    pub unsafe fn refill(&mut self, count: usize) -> usize {
+        unsafe {
+            let mut moved = 0;
+            while moved < count && self.len < CLEAN_POOL_MAX {
+                let Some(phys) = self.inner.allocate_one() else {
+                    break;
+                };
+                self.frames[self.len] = self.frames[self.clean];
+                self.frames[self.clean] = phys;
+                self.clean += 1;
+                self.len += 1;
+                moved += 1;
+            }
+            moved
+        }
+    }
+
+    /// Zero up to `count` frames of the pool that are not zeroed yet, returning how many frames
+    /// were zeroed
+    pub unsafe fn zero_pending(&mut self, count: usize) -> usize {
+        unsafe {
+            let zeroed = count.min(self.len - self.clean);
+            for &phys in &self.frames[self.clean..self.clean + zeroed] {
+                A::write_bytes(A::phys_to_virt(phys), 0, A::PAGE_SIZE);
+            }
+            self.clean += zeroed;
+            zeroed
+        }
+    }
+
+    /// Allocate one frame without zeroing it, for callers that overwrite all of it anyway.
+    /// Frames that are not zeroed yet are handed out first.
+    pub unsafe fn allocate_uninit(&mut self) -> Option<PhysicalAddress> {
+        unsafe {
+            if self.len > self.clean {
+                self.len -= 1;
+                Some(self.frames[self.len])
+            } else {
+                self.allocate_one()
+            }
+        }
+    }
+
+    fn pool(&self) -> &[PhysicalAddress] {
+        &self.frames[..self.len]
+    }
+}
+
+impl<A: Arch, F: FrameAllocator> FrameAllocator for CleanPoolAllocator<A, F> {
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        unsafe {
+            if count.data() != 1 || self.clean == 0 {
+                return self.inner.allocate(count);
+            }
+            let phys = self.frames[self.clean - 1];
+            self.frames[self.clean - 1] = self.frames[self.len - 1];
+            self.clean -= 1;
+            self.len -= 1;
+            Some(phys)
+        }
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        unsafe {
+            if count.data() == 1 && self.len < CLEAN_POOL_MAX {
+                self.frames[self.len] = address;
+                self.len += 1;
+            } else {
+                self.inner.free(address, count);
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate_one(&mut self) -> Option<PhysicalAddress> {
+        unsafe { self.allocate(FrameCount::new(1)) }
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        unsafe {
+            // Frames in the pool are still allocated from the inner allocator
+            let inner = self.inner.usage();
+            FrameUsage::new(
+                FrameCount::new(inner.used().data() - self.len),
+                inner.total(),
+            )
+        }
+    }
+
+    unsafe fn pending_zero(&self) -> FrameCount {
+        unsafe { FrameCount::new(self.inner.pending_zero().data() + self.len - self.clean) }
+    }
+
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        unsafe {
+            if self.pool().contains(&phys) {
+                Some(false)
+            } else {
+                self.inner.is_allocated(phys)
+            }
+        }
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        unsafe {
+            let pooled = self.pool().iter().filter(|&&phys| phys < limit).count();
+            FrameCount::new(self.inner.free_below(limit).data() + pooled)
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        unsafe { self.inner.managed_range() }
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use super::CleanPoolAllocator;
+    use crate::{Arch, EmulateArch, FrameAllocator};
+
+    type A = EmulateArch;
+
+    #[track_caller]
+    unsafe fn check_usage(allocator: &impl FrameAllocator, used: usize, pending: usize) {
+        unsafe {
+            let usage = allocator.usage();
+            assert_eq!(usage.used().data(), used);
+            assert_eq!(
+                usage.used().data() + usage.free().data(),
+                usage.total().data()
+            );
+            assert_eq!(allocator.pending_zero().data(), pending);
+            assert!(pending <= usage.free().data());
+        }
+    }
+
+    #[test]
+    fn accounting() {
+        unsafe {
+            let mut allocator = CleanPoolAllocator::<A, _>::new(A::init_test());
+            let used = allocator.usage().used().data();
+            assert_eq!(allocator.refill(8), 8);
+            check_usage(&allocator, used, 0);
+
+            let phys = allocator.allocate_one().unwrap();
+            check_usage(&allocator, used + 1, 0);
+            assert_eq!(allocator.is_allocated(phys), Some(true));
+            A::write(A::phys_to_virt(phys), usize::MAX);
+            allocator.free_one(phys);
+            check_usage(&allocator, used, 1);
+            assert_eq!(allocator.is_allocated(phys), Some(false));
+
+            // Handed out unzeroed, but used all the same
+            assert_eq!(allocator.allocate_uninit(), Some(phys));
+            check_usage(&allocator, used + 1, 0);
+            assert_eq!(A::read::<usize>(A::phys_to_virt(phys)), usize::MAX);
+
+            allocator.free_one(phys);
+            assert_eq!(allocator.zero_pending(4), 1);
+            check_usage(&allocator, used, 0);
+            for _ in 0..8 {
+                let phys = allocator.allocate_one().unwrap();
+                assert_eq!(A::read::<usize>(A::phys_to_virt(phys)), 0);
+            }
+            check_usage(&allocator, used + 8, 0);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{FrameAllocator, FrameCount, FrameUsage, PhysRange, PhysicalAddress};
+
+/// Which allocations a [`FaultInjectAllocator`] fails, counted from 1
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultPolicy {
+    Never,
+    /// Fail every allocation after this many have succeeded
+    FailAfter(usize),
+    /// Fail only this allocation
+    FailOn(usize),
+}
+
+/// Frame allocator wrapper that fails allocations deterministically according to a
+/// [`FaultPolicy`], to test that callers recover from running out of frames.
+///
+/// Failed allocations never reach the inner allocator, everything else is forwarded to it.
+pub struct FaultInjectAllocator<F> {
+    inner: F,
+    policy: FaultPolicy,
+    allocations: usize,
+}
+
+impl<F: FrameAllocator> FaultInjectAllocator<F> {
+    pub fn new(inner: F, policy: FaultPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            allocations: 0,
+        }
+    }
+
+    /// Replace the policy, counting allocations from the next one again
+    pub fn set_policy(&mut self, policy: FaultPolicy) {
+        self.policy = policy;
+        self.allocations = 0;
+    }
+
+    /// Number of allocations attempted since the policy was set, including failed ones
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn fail_next(&mut self) -> bool {
+        self.allocations += 1;
+        match self.policy {
+            FaultPolicy::Never => false,
+            FaultPolicy::FailAfter(successes) => self.allocations > successes,
+            FaultPolicy::FailOn(n) => self.allocations == n,
+        }
+    }
+}
+
+impl<F: FrameAllocator> FrameAllocator for FaultInjectAllocator<F> {
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        if self.fail_next() {
+            return None;
+        }
+        unsafe { self.inner.allocate(count) }
+    }
+
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        if self.fail_next() {
+            return None;
+        }
+        unsafe { self.inner.allocate_at(phys, count) }
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        unsafe { self.inner.free(address, count) }
+    }
+
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate_one(&mut self) -> Option<PhysicalAddress> {
+        unsafe { self.allocate(FrameCount::new(1)) }
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        unsafe { self.inner.usage() }
+    }
+
+    unsafe fn pending_zero(&self) -> FrameCount {
+        unsafe { self.inner.pending_zero() }
+    }
+
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        unsafe { self.inner.is_allocated(phys) }
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        unsafe { self.inner.free_below(limit) }
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        unsafe { self.inner.managed_range() }
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use super::{FaultInjectAllocator, FaultPolicy};
+    use crate::{
+        Arch, EmulateArch, FrameAllocator, MapOptions, PageFlags, PageMapper, PhysicalAddress,
+        TableKind, TrackingAllocator, VirtualAddress, MEGABYTE,
+    };
+
+    type A = EmulateArch;
+
+    #[test]
+    fn policy() {
+        unsafe {
+            let mut allocator = FaultInjectAllocator::new(A::init_test(), FaultPolicy::FailOn(2));
+            assert!(allocator.allocate_one().is_some());
+            assert!(allocator.allocate_one().is_none());
+            assert!(allocator.allocate_one().is_some());
+
+            allocator.set_policy(FaultPolicy::FailAfter(1));
+            assert!(allocator.allocate_one().is_some());
+            assert!(allocator.allocate_one().is_none());
+            assert!(allocator.allocate_one().is_none());
+            assert_eq!(allocator.allocations(), 3);
+        }
+    }
+
+    #[test]
+    fn map_range_rollback() {
+        unsafe {
+            let mut tracking = TrackingAllocator::<A, _>::new(A::init_test());
+            let mut allocator = FaultInjectAllocator::new(&mut tracking, FaultPolicy::Never);
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            let flags = PageFlags::new().write(true).user(true);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            mapper
+                .map_phys(VirtualAddress::new(MEGABYTE), phys, flags)
+                .unwrap()
+                .ignore();
+            let checksum = mapper.checksum();
+            let used = mapper.allocator().usage().used().data();
+
+            // The first page needs a new last level table, the second one a table of each level
+            // below the top, and the third frame fails halfway through mapping it
+            let virt = VirtualAddress::new((1 << 30) - A::PAGE_SIZE);
+            mapper.allocator_mut().set_policy(FaultPolicy::FailOn(3));
+            assert!(mapper
+                .map_range(virt, phys, 2, flags, MapOptions::default(), ())
+                .is_err());
+            assert_eq!(mapper.allocator().allocations(), 3);
+            assert_eq!(mapper.checksum(), checksum);
+            assert_eq!(mapper.allocator().usage().used().data(), used);
+            drop(mapper);
+            assert_eq!(tracking.leaked().count(), A::PAGE_LEVELS);
+        }
+    }
+}
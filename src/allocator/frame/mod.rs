@@ -1,9 +1,32 @@
-use crate::PhysicalAddress;
+use core::ops::Add;
 
-pub use self::{buddy::*, bump::*};
+use crate::{Arch, MemoryArea, PhysRange, PhysicalAddress};
+
+pub use self::{buddy::*, bump::*, clean_pool::*, fault_inject::*, tiny::*, tracking::*, zone::*};
 
 mod buddy;
 mod bump;
+mod clean_pool;
+mod fault_inject;
+mod tiny;
+mod tracking;
+mod zone;
+
+/// Number of buckets in a fragmentation histogram, runs of `1 << (MAX_ORDER - 1)` frames or more
+/// are counted in the last bucket
+pub const MAX_ORDER: usize = 11;
+
+/// Histogram bucket for a free run of `count` frames, the order of the largest power of two it
+/// contains
+fn run_order(count: usize) -> usize {
+    (count.ilog2() as usize).min(MAX_ORDER - 1)
+}
+
+/// Number of whole frames in `area`, rounded down. Frame counts of memory areas are always
+/// computed per area with this, so totals add up the same way everywhere.
+pub fn area_frame_count<A: Arch>(area: &MemoryArea) -> usize {
+    area.size / A::PAGE_SIZE
+}
 
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
@@ -19,7 +42,7 @@ impl FrameCount {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct FrameUsage {
     used: FrameCount,
     total: FrameCount,
@@ -43,6 +66,17 @@ impl FrameUsage {
     }
 }
 
+impl Add for FrameUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(
+            FrameCount(self.used.0 + other.used.0),
+            FrameCount(self.total.0 + other.total.0),
+        )
+    }
+}
+
 pub trait FrameAllocator {
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress>;
 
@@ -58,26 +92,144 @@ pub trait FrameAllocator {
         }
     }
 
+    /// Allocate exactly the `count` frames starting at `phys`, such as when restoring a saved
+    /// address space. Fails if any of them is not free. Unsupported by default.
+    unsafe fn allocate_at(
+        &mut self,
+        _phys: PhysicalAddress,
+        _count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        None
+    }
+
+    /// Allocate one frame and fill it with `src` through the physmap, followed by zeroes if
+    /// `src` is shorter than a page, so that it never holds stale contents. Bytes of `src` past
+    /// the page size are ignored.
+    unsafe fn allocate_from<A: Arch>(&mut self, src: &[u8]) -> Option<PhysicalAddress>
+    where
+        Self: Sized,
+    {
+        unsafe {
+            let phys = self.allocate_one()?;
+            let virt = A::phys_to_virt(phys);
+            let len = src.len().min(A::PAGE_SIZE);
+            A::write_slice(virt, &src[..len]);
+            if len < A::PAGE_SIZE {
+                A::write_bytes(virt.add(len), 0, A::PAGE_SIZE - len);
+            }
+            Some(phys)
+        }
+    }
+
+    /// Frames are counted as used in `usage` as soon as they are handed out, whether they were
+    /// zeroed or not, so that used and free frames always add up to the total
     unsafe fn usage(&self) -> FrameUsage;
+
+    /// Number of free frames that still have to be zeroed before they can be handed out zeroed,
+    /// such as freed frames in a [`CleanPoolAllocator`]. These are included in the free frames.
+    unsafe fn pending_zero(&self) -> FrameCount {
+        FrameCount::new(0)
+    }
+
+    /// Check whether the frame at `phys` is currently allocated, to catch double frees and
+    /// dangling frames. Returns `None` if `phys` is not managed by the allocator, or if the
+    /// allocator does not keep track.
+    unsafe fn is_allocated(&self, _phys: PhysicalAddress) -> Option<bool> {
+        None
+    }
+
+    /// Count the free frames that lie entirely below `limit`, such as those usable for DMA by
+    /// devices with a restricted address width. Reports none by default.
+    unsafe fn free_below(&self, _limit: PhysicalAddress) -> FrameCount {
+        FrameCount::new(0)
+    }
+
+    /// Size of each frame, the page size of the architecture the allocator was created for.
+    fn page_size(&self) -> usize;
+
+    /// Span of physical memory managed by the allocator, from its lowest to its highest frame,
+    /// such as to validate addresses before freeing them. Not every frame in the span has to be
+    /// managed.
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        None
+    }
 }
 
 impl<T> FrameAllocator for &mut T
 where
     T: FrameAllocator,
 {
+    #[cfg_attr(feature = "track-caller", track_caller)]
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
         unsafe { T::allocate(self, count) }
     }
     unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
         unsafe { T::free(self, address, count) }
     }
+    #[cfg_attr(feature = "track-caller", track_caller)]
     unsafe fn allocate_one(&mut self) -> Option<PhysicalAddress> {
         unsafe { T::allocate_one(self) }
     }
     unsafe fn free_one(&mut self, address: PhysicalAddress) {
         unsafe { T::free_one(self, address) }
     }
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe { T::allocate_at(self, phys, count) }
+    }
     unsafe fn usage(&self) -> FrameUsage {
         unsafe { T::usage(self) }
     }
+    unsafe fn pending_zero(&self) -> FrameCount {
+        unsafe { T::pending_zero(self) }
+    }
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        unsafe { T::is_allocated(self, phys) }
+    }
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        unsafe { T::free_below(self, limit) }
+    }
+    fn page_size(&self) -> usize {
+        T::page_size(self)
+    }
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        unsafe { T::managed_range(self) }
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use crate::{Arch, EmulateArch, FrameAllocator};
+
+    type A = EmulateArch;
+
+    #[test]
+    fn allocate_from() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let src: Vec<u8> = (0..100).collect();
+
+            // Dirty a frame first, so stale contents would be noticed when it is reused
+            let phys = allocator.allocate_one().unwrap();
+            A::write_bytes(A::phys_to_virt(phys), 0xFF, A::PAGE_SIZE);
+            allocator.free_one(phys);
+
+            let phys = allocator.allocate_from::<A>(&src).unwrap();
+            let virt = A::phys_to_virt(phys);
+            for i in 0..A::PAGE_SIZE {
+                let expected = src.get(i).copied().unwrap_or(0);
+                assert_eq!(A::read::<u8>(virt.add(i)), expected);
+            }
+
+            // Longer sources are cut off at the page size
+            let src = vec![0xAB; 2 * A::PAGE_SIZE];
+            let phys = allocator.allocate_from::<A>(&src).unwrap();
+            let virt = A::phys_to_virt(phys);
+            assert_eq!(A::read::<u8>(virt.add(A::PAGE_SIZE - 1)), 0xAB);
+        }
+    }
 }
@@ -0,0 +1,387 @@
+use core::marker::PhantomData;
+
+use super::run_order;
+use crate::{
+    area_frame_count, Arch, BumpAllocator, FrameAllocator, FrameCount, FrameUsage, MemoryArea,
+    PhysicalAddress, MAX_ORDER,
+};
+
+/// Maximum number of separate free runs a [`TinyAllocator`] can track
+pub const TINY_ALLOCATOR_RUNS: usize = 64;
+
+/// Minimal frame allocator for memory constrained targets, with a fixed size list of free runs
+/// and first-fit allocation.
+///
+/// Freed frames are merged with adjacent free runs. A free that would need a new run when all
+/// [`TINY_ALLOCATOR_RUNS`] are in use can not be tracked, so those frames are leaked instead, and
+/// counted in [`Self::leaked`].
+pub struct TinyAllocator<A> {
+    // Free runs of (first frame, frame count) sorted by first frame, never adjacent
+    runs: [(usize, usize); TINY_ALLOCATOR_RUNS],
+    len: usize,
+    total: usize,
+    leaked: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> TinyAllocator<A> {
+    /// Create an allocator without any frames, which can be added with [`Self::add`]
+    pub fn empty() -> Self {
+        Self {
+            runs: [(0, 0); TINY_ALLOCATOR_RUNS],
+            len: 0,
+            total: 0,
+            leaked: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Create an allocator over the frames not yet used by `bump_allocator`
+    pub unsafe fn new(bump_allocator: BumpAllocator<A>) -> Self {
+        unsafe {
+            let mut allocator = Self::empty();
+            let (areas, mut offset) = bump_allocator.free_areas();
+            for area in areas.iter() {
+                let area = MemoryArea {
+                    base: area.base.add(offset),
+                    size: area.size - offset,
+                };
+                allocator.add(area.base, FrameCount::new(area_frame_count::<A>(&area)));
+                offset = 0;
+            }
+            allocator
+        }
+    }
+
+    /// Add `count` frames starting at `base` to the allocator
+    pub unsafe fn add(&mut self, base: PhysicalAddress, count: FrameCount) {
+        unsafe {
+            self.total += count.data();
+            self.free(base, count);
+        }
+    }
+
+    /// Allocate `count` frames starting at a multiple of `align` frames, which must be a power of
+    /// two. The frames skipped for alignment stay free, unless the run list is full.
+    pub unsafe fn allocate_aligned(
+        &mut self,
+        count: FrameCount,
+        align: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            let (count, align) = (count.data(), align.data());
+            if count == 0 {
+                return None;
+            }
+            let (i, start) = self
+                .runs()
+                .iter()
+                .enumerate()
+                .find_map(|(i, &(start, len))| {
+                    let aligned = start.next_multiple_of(align);
+                    (aligned + count <= start + len).then_some((i, aligned))
+                })?;
+            let (run_start, run_len) = self.runs[i];
+            self.remove(i);
+            self.free(
+                PhysicalAddress::new(run_start << A::PAGE_SHIFT),
+                FrameCount::new(start - run_start),
+            );
+            self.free(
+                PhysicalAddress::new((start + count) << A::PAGE_SHIFT),
+                FrameCount::new(run_start + run_len - start - count),
+            );
+
+            let base = PhysicalAddress::new(start << A::PAGE_SHIFT);
+            Self::zero(base, count);
+            Some(base)
+        }
+    }
+
+    /// Number of frames lost because they were freed while the run list was full
+    pub fn leaked(&self) -> FrameCount {
+        FrameCount::new(self.leaked)
+    }
+
+    /// Count the free runs by order into `out`, which shows whether large contiguous allocations
+    /// can still succeed
+    pub unsafe fn fragmentation_histogram(&self, out: &mut [usize; MAX_ORDER]) {
+        *out = [0; MAX_ORDER];
+        for &(_, len) in self.runs() {
+            out[run_order(len)] += 1;
+        }
+    }
+
+    unsafe fn zero(base: PhysicalAddress, count: usize) {
+        unsafe {
+            for page in 0..count {
+                let page_virt = A::phys_to_virt(base.add(page << A::PAGE_SHIFT));
+                A::write_bytes(page_virt, 0, A::PAGE_SIZE);
+            }
+        }
+    }
+
+    fn runs(&self) -> &[(usize, usize)] {
+        &self.runs[..self.len]
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.runs.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+    }
+
+    fn insert(&mut self, i: usize, run: (usize, usize)) {
+        self.runs.copy_within(i..self.len, i + 1);
+        self.runs[i] = run;
+        self.len += 1;
+    }
+}
+
+impl<A: Arch> FrameAllocator for TinyAllocator<A> {
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        unsafe {
+            let count = count.data();
+            if count == 0 {
+                return None;
+            }
+            let i = self.runs().iter().position(|&(_, len)| len >= count)?;
+            let (start, len) = self.runs[i];
+            if len == count {
+                self.remove(i);
+            } else {
+                self.runs[i] = (start + count, len - count);
+            }
+
+            let base = PhysicalAddress::new(start << A::PAGE_SHIFT);
+            Self::zero(base, count);
+            Some(base)
+        }
+    }
+
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            let (start, count) = (phys.data() >> A::PAGE_SHIFT, count.data());
+            if count == 0 || phys.data() & A::PAGE_OFFSET_MASK != 0 {
+                return None;
+            }
+            let i = self.runs().iter().position(|&(run_start, run_len)| {
+                run_start <= start && start + count <= run_start + run_len
+            })?;
+            let (run_start, run_len) = self.runs[i];
+            self.remove(i);
+            self.free(
+                PhysicalAddress::new(run_start << A::PAGE_SHIFT),
+                FrameCount::new(start - run_start),
+            );
+            self.free(
+                PhysicalAddress::new((start + count) << A::PAGE_SHIFT),
+                FrameCount::new(run_start + run_len - start - count),
+            );
+
+            Self::zero(phys, count);
+            Some(phys)
+        }
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        let start = address.data() >> A::PAGE_SHIFT;
+        let count = count.data();
+        if count == 0 {
+            return;
+        }
+
+        // Index of the first run after the freed frames
+        let i = self
+            .runs()
+            .partition_point(|&(run_start, _)| run_start < start);
+        debug_assert!(
+            (i == 0 || self.runs[i - 1].0 + self.runs[i - 1].1 <= start)
+                && (i == self.len || start + count <= self.runs[i].0),
+            "frames {:#x}+{} freed while already free",
+            address.data(),
+            count
+        );
+        let merge_prev = i > 0 && {
+            let (prev_start, prev_len) = self.runs[i - 1];
+            prev_start + prev_len == start
+        };
+        let merge_next = i < self.len && start + count == self.runs[i].0;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.runs[i - 1].1 += count + self.runs[i].1;
+                self.remove(i);
+            }
+            (true, false) => self.runs[i - 1].1 += count,
+            (false, true) => self.runs[i] = (start, count + self.runs[i].1),
+            (false, false) if self.len < TINY_ALLOCATOR_RUNS => self.insert(i, (start, count)),
+            (false, false) => self.leaked += count,
+        }
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        let free = self.runs().iter().map(|&(_, len)| len).sum::<usize>();
+        FrameUsage::new(
+            FrameCount::new(self.total - free),
+            FrameCount::new(self.total),
+        )
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        let limit = limit.data() >> A::PAGE_SHIFT;
+        let free = self
+            .runs()
+            .iter()
+            .map(|&(start, len)| (start + len).min(limit).saturating_sub(start))
+            .sum::<usize>();
+        FrameCount::new(free)
+    }
+
+    fn page_size(&self) -> usize {
+        A::PAGE_SIZE
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use super::{TinyAllocator, TINY_ALLOCATOR_RUNS};
+    use crate::{Arch, EmulateArch, FrameAllocator, FrameCount, PhysicalAddress, MAX_ORDER};
+
+    type A = EmulateArch;
+
+    // Frames taken from the emulated machine, so that allocations can be zeroed
+    unsafe fn tiny(count: usize) -> (TinyAllocator<A>, PhysicalAddress) {
+        unsafe {
+            let mut buddy = A::init_test();
+            let base = buddy.allocate(FrameCount::new(count)).unwrap();
+            let mut tiny = TinyAllocator::<A>::empty();
+            tiny.add(base, FrameCount::new(count));
+            (tiny, base)
+        }
+    }
+
+    fn page(i: usize) -> usize {
+        i * A::PAGE_SIZE
+    }
+
+    #[test]
+    fn allocate() {
+        unsafe {
+            let (mut tiny, base) = tiny(16);
+            assert_eq!(tiny.usage().total().data(), 16);
+
+            assert_eq!(tiny.allocate(FrameCount::new(0)), None);
+            assert_eq!(tiny.allocate(FrameCount::new(4)), Some(base));
+            assert_eq!(tiny.allocate(FrameCount::new(13)), None);
+            assert_eq!(tiny.allocate(FrameCount::new(10)), Some(base.add(page(4))));
+            assert_eq!(tiny.allocate_one(), Some(base.add(page(14))));
+            assert_eq!(tiny.usage().used().data(), 15);
+            assert_eq!(tiny.allocate_one(), Some(base.add(page(15))));
+            assert_eq!(tiny.allocate_one(), None);
+            assert_eq!(tiny.usage().free().data(), 0);
+            assert_eq!(A::read::<usize>(A::phys_to_virt(base.add(page(15)))), 0);
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "already free")]
+    fn double_free() {
+        unsafe {
+            let (mut tiny, base) = tiny(16);
+            let phys = tiny.allocate(FrameCount::new(4)).unwrap();
+            assert_eq!(phys, base);
+            tiny.free(phys.add(page(2)), FrameCount::new(4));
+        }
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        unsafe {
+            let (mut tiny, base) = tiny(64);
+            let aligned = tiny
+                .allocate_aligned(FrameCount::new(4), FrameCount::new(16))
+                .unwrap();
+            assert_eq!(aligned.data() % page(16), 0);
+            let skipped = (aligned.data() - base.data()) / A::PAGE_SIZE;
+            assert!(skipped < 16);
+
+            // Frames skipped for alignment are still free
+            assert_eq!(tiny.usage().used().data(), 4);
+            assert_eq!(tiny.free_below(aligned).data(), skipped);
+            assert_eq!(
+                tiny.allocate_aligned(FrameCount::new(60), FrameCount::new(1)),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn free_coalesces() {
+        unsafe {
+            let (mut tiny, base) = tiny(16);
+            assert_eq!(tiny.allocate(FrameCount::new(16)), Some(base));
+
+            tiny.free(base.add(page(4)), FrameCount::new(2));
+            tiny.free(base.add(page(8)), FrameCount::new(2));
+            assert_eq!(tiny.runs().len(), 2);
+
+            // Merges with the previous run, then bridges the gap to the next
+            tiny.free(base.add(page(6)), FrameCount::new(1));
+            assert_eq!(tiny.runs().len(), 2);
+            tiny.free(base.add(page(7)), FrameCount::new(1));
+            assert_eq!(tiny.runs(), &[(base.data() / A::PAGE_SIZE + 4, 6)]);
+
+            // Merges with the next run
+            tiny.free(base, FrameCount::new(4));
+            assert_eq!(tiny.runs(), &[(base.data() / A::PAGE_SIZE, 10)]);
+            assert_eq!(tiny.allocate(FrameCount::new(10)), Some(base));
+        }
+    }
+
+    #[test]
+    fn fragmentation_histogram() {
+        unsafe {
+            let (mut tiny, base) = tiny(2048);
+            let mut histogram = [usize::MAX; MAX_ORDER];
+            tiny.fragmentation_histogram(&mut histogram);
+            assert_eq!(histogram, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+            // Leave free runs of 1, 3, 4, 7, 9 and 1001 frames, separated by used frames
+            assert_eq!(tiny.allocate(FrameCount::new(2048)), Some(base));
+            let mut offset = 0;
+            for (free, used) in [(1, 1), (3, 2), (4, 3), (7, 4), (9, 5), (1001, 0)] {
+                tiny.free(base.add(page(offset)), FrameCount::new(free));
+                offset += free + used;
+            }
+            tiny.fragmentation_histogram(&mut histogram);
+            assert_eq!(histogram, [1, 1, 2, 1, 0, 0, 0, 0, 0, 1, 0]);
+        }
+    }
+
+    #[test]
+    fn capacity_exceeded() {
+        unsafe {
+            let count = 2 * (TINY_ALLOCATOR_RUNS + 1);
+            let (mut tiny, base) = tiny(count);
+            assert_eq!(tiny.allocate(FrameCount::new(count)), Some(base));
+
+            // Freeing every other frame needs a new run each time
+            for i in 0..=TINY_ALLOCATOR_RUNS {
+                tiny.free(base.add(page(2 * i)), FrameCount::new(1));
+            }
+            assert_eq!(tiny.runs().len(), TINY_ALLOCATOR_RUNS);
+            assert_eq!(tiny.leaked().data(), 1);
+            assert_eq!(tiny.usage().free().data(), TINY_ALLOCATOR_RUNS);
+
+            // Frames adjacent to a run can still be freed
+            tiny.free(base.add(page(1)), FrameCount::new(1));
+            assert_eq!(tiny.runs().len(), TINY_ALLOCATOR_RUNS - 1);
+            assert_eq!(tiny.free_below(base.add(page(4))).data(), 3);
+        }
+    }
+}
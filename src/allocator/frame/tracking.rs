@@ -0,0 +1,250 @@
+use core::marker::PhantomData;
+#[cfg(feature = "track-caller")]
+use core::panic::Location;
+
+use crate::{Arch, FrameAllocator, FrameCount, FrameUsage, PhysRange, PhysicalAddress};
+
+/// Maximum number of outstanding allocations a [`TrackingAllocator`] can record
+pub const TRACKING_ALLOCATOR_MAX: usize = 256;
+
+/// An outstanding allocation recorded by a [`TrackingAllocator`]
+#[derive(Clone, Copy, Debug)]
+pub struct Allocation {
+    pub base: PhysicalAddress,
+    pub count: FrameCount,
+    /// Code that made the allocation
+    #[cfg(feature = "track-caller")]
+    pub location: &'static Location<'static>,
+    /// Tag set with [`TrackingAllocator::set_tag`] when the allocation was made
+    #[cfg(feature = "track-caller")]
+    pub tag: Option<&'static str>,
+}
+
+/// Frame allocator wrapper that records every outstanding allocation, so frames that are never
+/// freed can be found with [`Self::leaked`].
+///
+/// With the `track-caller` feature, each allocation also records the location of its caller and
+/// the current tag. Allocations made when all [`TRACKING_ALLOCATOR_MAX`] records are in use are
+/// not recorded, and only counted in [`Self::untracked`].
+pub struct TrackingAllocator<A, F> {
+    inner: F,
+    allocations: [Option<Allocation>; TRACKING_ALLOCATOR_MAX],
+    untracked: usize,
+    #[cfg(feature = "track-caller")]
+    tag: Option<&'static str>,
+    phantom: PhantomData<fn() -> A>,
+}
+
+impl<A: Arch, F: FrameAllocator> TrackingAllocator<A, F> {
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            allocations: [None; TRACKING_ALLOCATOR_MAX],
+            untracked: 0,
+            #[cfg(feature = "track-caller")]
+            tag: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Allocations that have not been freed yet
+    pub fn leaked(&self) -> impl Iterator<Item = &Allocation> {
+        self.allocations.iter().flatten()
+    }
+
+    /// Number of allocations that could not be recorded, including the rest of partially freed
+    /// allocations that did not fit anymore
+    pub fn untracked(&self) -> usize {
+        self.untracked
+    }
+
+    /// Record `tag` with the following allocations, such as the name of the code path that makes
+    /// them when the caller location alone is not telling
+    #[cfg(feature = "track-caller")]
+    pub fn set_tag(&mut self, tag: Option<&'static str>) {
+        self.tag = tag;
+    }
+
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    fn record(&mut self, base: PhysicalAddress, count: FrameCount) {
+        let allocation = Allocation {
+            base,
+            count,
+            #[cfg(feature = "track-caller")]
+            location: Location::caller(),
+            #[cfg(feature = "track-caller")]
+            tag: self.tag,
+        };
+        match self.allocations.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(allocation),
+            None => self.untracked += 1,
+        }
+    }
+
+    fn forget(&mut self, base: PhysicalAddress, count: FrameCount) {
+        let size = count.data() * A::PAGE_SIZE;
+        let start = base.data();
+        let Some(i) = self.allocations.iter().position(|slot| {
+            slot.is_some_and(|allocation| {
+                let allocation_start = allocation.base.data();
+                start >= allocation_start
+                    && start + size <= allocation_start + allocation.count.data() * A::PAGE_SIZE
+            })
+        }) else {
+            return;
+        };
+
+        // Frees of part of an allocation leave the rest of it recorded
+        let allocation = self.allocations[i].take().expect("slot must be used");
+        let before = (start - allocation.base.data()) / A::PAGE_SIZE;
+        let after = allocation.count.data() - before - count.data();
+        if before > 0 {
+            self.allocations[i] = Some(Allocation {
+                count: FrameCount::new(before),
+                ..allocation
+            });
+        }
+        if after > 0 {
+            let rest = Allocation {
+                base: PhysicalAddress::new(start + size),
+                count: FrameCount::new(after),
+                ..allocation
+            };
+            match self.allocations.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => *slot = Some(rest),
+                None => self.untracked += 1,
+            }
+        }
+    }
+}
+
+impl<A: Arch, F: FrameAllocator> FrameAllocator for TrackingAllocator<A, F> {
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        unsafe {
+            let base = self.inner.allocate(count)?;
+            self.record(base, count);
+            Some(base)
+        }
+    }
+
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            let base = self.inner.allocate_at(phys, count)?;
+            self.record(base, count);
+            Some(base)
+        }
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        unsafe {
+            self.forget(address, count);
+            self.inner.free(address, count);
+        }
+    }
+
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate_one(&mut self) -> Option<PhysicalAddress> {
+        unsafe { self.allocate(FrameCount::new(1)) }
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        unsafe { self.inner.usage() }
+    }
+
+    unsafe fn pending_zero(&self) -> FrameCount {
+        unsafe { self.inner.pending_zero() }
+    }
+
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        unsafe { self.inner.is_allocated(phys) }
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        unsafe { self.inner.free_below(limit) }
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        unsafe { self.inner.managed_range() }
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use super::{TrackingAllocator, TRACKING_ALLOCATOR_MAX};
+    use crate::{Arch, EmulateArch, FrameAllocator, FrameCount};
+
+    type A = EmulateArch;
+
+    #[test]
+    fn leaked() {
+        unsafe {
+            let mut allocator = TrackingAllocator::<A, _>::new(A::init_test());
+            let freed = allocator.allocate_one().unwrap();
+            #[cfg(feature = "track-caller")]
+            allocator.set_tag(Some("leaked"));
+            #[cfg(feature = "track-caller")]
+            let line = line!() + 1;
+            let leaked = allocator.allocate_one().unwrap();
+            #[cfg(feature = "track-caller")]
+            allocator.set_tag(None);
+            let partial = allocator.allocate(FrameCount::new(4)).unwrap();
+            allocator.free_one(freed);
+            allocator.free(partial, FrameCount::new(3));
+
+            let mut leaks: Vec<_> = allocator.leaked().copied().collect();
+            leaks.sort_by_key(|allocation| allocation.base);
+            assert_eq!(leaks.len(), 2);
+            assert_eq!(leaks[0].base, leaked);
+            assert_eq!(leaks[0].count.data(), 1);
+            assert_eq!(leaks[1].base, partial.add(3 * A::PAGE_SIZE));
+            assert_eq!(leaks[1].count.data(), 1);
+            assert_eq!(allocator.untracked(), 0);
+
+            #[cfg(feature = "track-caller")]
+            {
+                assert_eq!(leaks[0].location.file(), file!());
+                assert_eq!(leaks[0].location.line(), line);
+                assert_eq!(leaks[1].location.line(), line + 3);
+                assert_eq!(leaks[0].tag, Some("leaked"));
+                assert_eq!(leaks[1].tag, None);
+            }
+        }
+    }
+
+    #[test]
+    fn untracked() {
+        unsafe {
+            let mut allocator = TrackingAllocator::<A, _>::new(A::init_test());
+            let split = allocator.allocate(FrameCount::new(3)).unwrap();
+            for _ in 1..TRACKING_ALLOCATOR_MAX {
+                allocator.allocate_one().unwrap();
+            }
+            assert_eq!(allocator.untracked(), 0);
+            allocator.allocate_one().unwrap();
+            assert_eq!(allocator.untracked(), 1);
+
+            // Freeing the middle of an allocation needs another record for the rest
+            allocator.free_one(split.add(A::PAGE_SIZE));
+            assert_eq!(allocator.untracked(), 2);
+            assert_eq!(allocator.leaked().count(), TRACKING_ALLOCATOR_MAX);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use crate::{
+    area_frame_count, Arch, FrameAllocator, FrameCount, FrameUsage, MemoryArea, PhysRange,
+    PhysicalAddress, TinyAllocator,
+};
+
+/// Number of zones a [`ZoneReport`] keeps individual usage for
+pub const ZONE_REPORT_MAX: usize = 8;
+
+/// Combined usage of several allocators, such as the zones of a multi-zone setup
+#[derive(Clone, Copy, Debug)]
+pub struct ZoneReport<'a> {
+    zones: [(&'a str, FrameUsage); ZONE_REPORT_MAX],
+    count: usize,
+    total: FrameUsage,
+}
+
+impl<'a> ZoneReport<'a> {
+    /// Sum the usage of all `zones`. Only the first [`ZONE_REPORT_MAX`] zones are kept by name,
+    /// but every zone is included in the total.
+    pub unsafe fn aggregate(
+        zones: impl Iterator<Item = (&'a str, &'a dyn FrameAllocator)>,
+    ) -> Self {
+        unsafe {
+            let empty = FrameUsage::new(FrameCount::new(0), FrameCount::new(0));
+            let mut report = Self {
+                zones: [("", empty); ZONE_REPORT_MAX],
+                count: 0,
+                total: empty,
+            };
+            for (name, allocator) in zones {
+                let usage = allocator.usage();
+                if report.count < ZONE_REPORT_MAX {
+                    report.zones[report.count] = (name, usage);
+                    report.count += 1;
+                }
+                report.total = report.total + usage;
+            }
+            report
+        }
+    }
+
+    pub fn zones(&self) -> &[(&'a str, FrameUsage)] {
+        &self.zones[..self.count]
+    }
+
+    pub fn zone(&self, name: &str) -> Option<FrameUsage> {
+        self.zones()
+            .iter()
+            .find(|(zone_name, _)| *zone_name == name)
+            .map(|(_, usage)| *usage)
+    }
+
+    pub fn total(&self) -> FrameUsage {
+        self.total
+    }
+}
+
+/// Number of zones a [`ZoneAllocator`] can have
+pub const ZONE_ALLOCATOR_MAX: usize = 4;
+
+struct Zone<A> {
+    name: &'static str,
+    area: MemoryArea,
+    min_align: usize,
+    allocator: TinyAllocator<A>,
+}
+
+impl<A> Zone<A> {
+    fn contains(&self, phys: PhysicalAddress) -> bool {
+        phys >= self.area.base && phys.data() < self.area.base.data() + self.area.size
+    }
+}
+
+/// Frame allocator that reserves zones of physical memory for allocations with a large
+/// alignment, such as a pool of huge pages, and serves everything else from a general allocator.
+///
+/// Plain allocations never come from a zone. [`Self::allocate_aligned`] tries the zones that are
+/// meant for the requested alignment first, preferring those with the largest `min_align`.
+pub struct ZoneAllocator<A, F> {
+    general: F,
+    zones: [Option<Zone<A>>; ZONE_ALLOCATOR_MAX],
+}
+
+impl<A: Arch, F: FrameAllocator> ZoneAllocator<A, F> {
+    pub fn new(general: F) -> Self {
+        Self {
+            general,
+            zones: [const { None }; ZONE_ALLOCATOR_MAX],
+        }
+    }
+
+    /// Move the frames of `area` from the general allocator into a new zone for allocations
+    /// aligned to `min_align` bytes or more. Fails if the frames are not all free in the general
+    /// allocator, or if all [`ZONE_ALLOCATOR_MAX`] zones are in use.
+    pub unsafe fn add_zone(
+        &mut self,
+        name: &'static str,
+        area: MemoryArea,
+        min_align: usize,
+    ) -> bool {
+        unsafe {
+            let Some(slot) = self.zones.iter_mut().find(|zone| zone.is_none()) else {
+                return false;
+            };
+            let count = FrameCount::new(area_frame_count::<A>(&area));
+            if self.general.allocate_at(area.base, count).is_none() {
+                return false;
+            }
+            let mut allocator = TinyAllocator::empty();
+            allocator.add(area.base, count);
+            *slot = Some(Zone {
+                name,
+                area,
+                min_align,
+                allocator,
+            });
+            true
+        }
+    }
+
+    /// Allocate `count` frames aligned to `align` bytes, a power of two, from the zone with the
+    /// largest `min_align` that is not above `align` and has room, or from the general allocator
+    /// otherwise
+    pub unsafe fn allocate_aligned(
+        &mut self,
+        count: FrameCount,
+        align: usize,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            let align_frames = FrameCount::new((align / A::PAGE_SIZE).max(1));
+            let mut candidates = [None; ZONE_ALLOCATOR_MAX];
+            for (candidate, zone) in candidates.iter_mut().zip(&self.zones) {
+                *candidate = zone
+                    .as_ref()
+                    .filter(|zone| zone.min_align <= align)
+                    .map(|zone| zone.min_align);
+            }
+            while let Some(i) = (0..ZONE_ALLOCATOR_MAX).max_by_key(|&i| candidates[i]) {
+                if candidates[i].is_none() {
+                    break;
+                }
+                candidates[i] = None;
+                let zone = self.zones[i].as_mut().expect("candidate zone must exist");
+                if let Some(base) = zone.allocator.allocate_aligned(count, align_frames) {
+                    return Some(base);
+                }
+            }
+            self.allocate_general_aligned(count, align_frames)
+        }
+    }
+
+    // Allocate enough extra frames to find an aligned start among them, then free the rest
+    unsafe fn allocate_general_aligned(
+        &mut self,
+        count: FrameCount,
+        align: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            let extra = align.data() - 1;
+            let base = self
+                .general
+                .allocate(FrameCount::new(count.data() + extra))?;
+            let aligned =
+                PhysicalAddress::new(base.data().next_multiple_of(align.data() * A::PAGE_SIZE));
+            let head = (aligned.data() - base.data()) / A::PAGE_SIZE;
+            if head > 0 {
+                self.general.free(base, FrameCount::new(head));
+            }
+            if extra > head {
+                self.general.free(
+                    aligned.add(count.data() * A::PAGE_SIZE),
+                    FrameCount::new(extra - head),
+                );
+            }
+            Some(aligned)
+        }
+    }
+
+    /// Names and allocators of the zones, for [`ZoneReport::aggregate`]
+    pub fn zones(&self) -> impl Iterator<Item = (&str, &dyn FrameAllocator)> {
+        self.zones
+            .iter()
+            .flatten()
+            .map(|zone| (zone.name, &zone.allocator as &dyn FrameAllocator))
+    }
+
+    pub fn general(&self) -> &F {
+        &self.general
+    }
+
+    fn zone_of(&mut self, phys: PhysicalAddress) -> Option<&mut Zone<A>> {
+        self.zones
+            .iter_mut()
+            .flatten()
+            .find(|zone| zone.contains(phys))
+    }
+}
+
+impl<A: Arch, F: FrameAllocator> FrameAllocator for ZoneAllocator<A, F> {
+    #[cfg_attr(feature = "track-caller", track_caller)]
+    unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
+        unsafe { self.general.allocate(count) }
+    }
+
+    unsafe fn allocate_at(
+        &mut self,
+        phys: PhysicalAddress,
+        count: FrameCount,
+    ) -> Option<PhysicalAddress> {
+        unsafe {
+            match self.zone_of(phys) {
+                Some(zone) => zone.allocator.allocate_at(phys, count),
+                None => self.general.allocate_at(phys, count),
+            }
+        }
+    }
+
+    unsafe fn free(&mut self, address: PhysicalAddress, count: FrameCount) {
+        unsafe {
+            match self.zone_of(address) {
+                Some(zone) => zone.allocator.free(address, count),
+                None => self.general.free(address, count),
+            }
+        }
+    }
+
+    unsafe fn usage(&self) -> FrameUsage {
+        // Zone frames count as used in the general allocator, so only their free frames differ
+        unsafe {
+            let general = self.general.usage();
+            let zone_free = self
+                .zones
+                .iter()
+                .flatten()
+                .map(|zone| zone.allocator.usage().free().data())
+                .sum::<usize>();
+            FrameUsage::new(
+                FrameCount::new(general.used().data() - zone_free),
+                general.total(),
+            )
+        }
+    }
+
+    unsafe fn is_allocated(&self, phys: PhysicalAddress) -> Option<bool> {
+        unsafe {
+            match self.zones.iter().flatten().find(|zone| zone.contains(phys)) {
+                // The frame is free if it adds to the free frames below the next one
+                Some(zone) => {
+                    let page = PhysicalAddress::new(phys.data() & !A::PAGE_OFFSET_MASK);
+                    let below = zone.allocator.free_below(page).data();
+                    let through = zone.allocator.free_below(page.add(A::PAGE_SIZE)).data();
+                    Some(below == through)
+                }
+                None => self.general.is_allocated(phys),
+            }
+        }
+    }
+
+    unsafe fn pending_zero(&self) -> FrameCount {
+        // Zones zero their frames on allocation
+        unsafe { self.general.pending_zero() }
+    }
+
+    unsafe fn free_below(&self, limit: PhysicalAddress) -> FrameCount {
+        unsafe {
+            let free = self
+                .zones
+                .iter()
+                .flatten()
+                .map(|zone| zone.allocator.free_below(limit).data())
+                .sum::<usize>();
+            FrameCount::new(self.general.free_below(limit).data() + free)
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.general.page_size()
+    }
+
+    unsafe fn managed_range(&self) -> Option<PhysRange> {
+        // Zones are carved out of the general allocator
+        unsafe { self.general.managed_range() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ZoneReport;
+    use crate::{FrameAllocator, FrameCount, FrameUsage, PhysicalAddress};
+
+    struct FixedUsage(FrameUsage);
+
+    impl FrameAllocator for FixedUsage {
+        unsafe fn allocate(&mut self, _count: FrameCount) -> Option<PhysicalAddress> {
+            None
+        }
+
+        unsafe fn free(&mut self, _address: PhysicalAddress, _count: FrameCount) {}
+
+        unsafe fn usage(&self) -> FrameUsage {
+            self.0
+        }
+
+        unsafe fn free_below(&self, _limit: PhysicalAddress) -> FrameCount {
+            self.0.free()
+        }
+
+        fn page_size(&self) -> usize {
+            4096
+        }
+    }
+
+    #[test]
+    fn aggregate() {
+        let dma = FixedUsage(FrameUsage::new(FrameCount::new(3), FrameCount::new(16)));
+        let normal = FixedUsage(FrameUsage::new(FrameCount::new(100), FrameCount::new(1000)));
+        let zones: [(&str, &dyn FrameAllocator); 2] = [("dma", &dma), ("normal", &normal)];
+        let report = unsafe { ZoneReport::aggregate(zones.into_iter()) };
+
+        assert_eq!(report.zones().len(), 2);
+        assert_eq!(report.zone("dma").unwrap().used().data(), 3);
+        assert_eq!(report.zone("dma").unwrap().total().data(), 16);
+        assert_eq!(report.zone("normal").unwrap().free().data(), 900);
+        assert!(report.zone("highmem").is_none());
+        assert_eq!(report.total().used().data(), 103);
+        assert_eq!(report.total().total().data(), 1016);
+        assert_eq!(report.total().free().data(), 913);
+    }
+
+    #[cfg(all(feature = "std", target_pointer_width = "64"))]
+    #[test]
+    fn zone_allocator() {
+        use super::ZoneAllocator;
+        use crate::{Arch, EmulateArch, MemoryArea, MEGABYTE};
+
+        type A = EmulateArch;
+
+        unsafe {
+            // The emulated machine is too small for a 1 GiB zone, so reserve a pool of 2 MiB
+            // pages instead
+            let mut allocator = ZoneAllocator::<A, _>::new(A::init_test());
+            let area = MemoryArea {
+                base: PhysicalAddress::new(16 * MEGABYTE),
+                size: 8 * MEGABYTE,
+            };
+            let huge = 2 * MEGABYTE;
+            assert!(allocator.add_zone("huge", area, huge));
+            assert!(!allocator.add_zone("overlap", area, huge));
+            let in_zone = |phys: PhysicalAddress| {
+                phys >= area.base && phys.data() < area.base.data() + area.size
+            };
+
+            let frames = FrameCount::new(huge / A::PAGE_SIZE);
+            for i in 0..4 {
+                let phys = allocator.allocate_aligned(frames, huge).unwrap();
+                assert_eq!(phys, area.base.add(i * huge));
+            }
+
+            // Small and less aligned allocations stay out of the zone
+            for _ in 0..16 {
+                assert!(!in_zone(allocator.allocate_one().unwrap()));
+            }
+            let phys = allocator
+                .allocate_aligned(FrameCount::new(2), 4 * A::PAGE_SIZE)
+                .unwrap();
+            assert!(!in_zone(phys));
+            assert_eq!(phys.data() % (4 * A::PAGE_SIZE), 0);
+
+            // With the zone used up, aligned allocations come from everywhere else
+            let phys = allocator.allocate_aligned(frames, huge).unwrap();
+            assert!(!in_zone(phys));
+            assert_eq!(phys.data() % huge, 0);
+
+            allocator.free(area.base, frames);
+            assert_eq!(allocator.allocate_aligned(frames, huge), Some(area.base));
+
+            let report = ZoneReport::aggregate(allocator.zones());
+            assert_eq!(report.zone("huge").unwrap().free().data(), 0);
+
+            // Zone frames are only counted once
+            let usage = allocator.usage();
+            assert_eq!(
+                usage.total().data(),
+                allocator.general().usage().total().data()
+            );
+            assert_eq!(
+                usage.used().data() + usage.free().data(),
+                usage.total().data()
+            );
+            allocator.free(area.base, frames);
+            assert_eq!(
+                allocator.usage().free().data(),
+                usage.free().data() + frames.data()
+            );
+
+            // Frames at a fixed address, inside and outside of the zone
+            assert_eq!(allocator.is_allocated(area.base), Some(false));
+            assert_eq!(allocator.is_allocated(area.base.add(huge)), Some(true));
+            let phys = area.base.add(A::PAGE_SIZE);
+            assert_eq!(allocator.allocate_at(phys, FrameCount::new(2)), Some(phys));
+            assert_eq!(allocator.is_allocated(phys), Some(true));
+            assert_eq!(allocator.is_allocated(area.base), Some(false));
+            assert_eq!(allocator.allocate_at(phys, FrameCount::new(1)), None);
+            let outside = PhysicalAddress::new(40 * MEGABYTE);
+            assert_eq!(
+                allocator.allocate_at(outside, FrameCount::new(1)),
+                Some(outside)
+            );
+            assert_eq!(allocator.is_allocated(outside), Some(true));
+        }
+    }
+}
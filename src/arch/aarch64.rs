@@ -6,6 +6,77 @@ use crate::{Arch, MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
 pub struct AArch64Arch;
 
 impl AArch64Arch {
+    // Attribute bits that may only change with break-before-make: the table/page flag, which
+    // selects the block size, AttrIndx, which selects the memory type, and shareability
+    const BREAK_BEFORE_MAKE_MASK: usize = 1 << 1 | 0b111 << 2 | 0b11 << 8;
+
+    // AttrIndx 0, used by all entries, must be normal write-back memory
+    const MAIR_ATTR0_MASK: u64 = 0xFF;
+    const MAIR_ATTR0_NORMAL: u64 = 0xFF;
+
+    // 4 KiB granules for both TTBR0 and TTBR1, matching PAGE_SHIFT. Everything else, such as
+    // table walk attributes, walk disables and tagging, is up to the kernel.
+    const TCR_GRANULE_MASK: u64 = 0b11 << 14 | 0b11 << 30;
+    const TCR_GRANULE_4K: u64 = 0b00 << 14 // TG0 = 4 KiB
+        | 0b10 << 30 // TG1 = 4 KiB
+        ;
+    // Region sizes of 40 to 48 bits, which walks start at level 0 for, matching PAGE_LEVELS.
+    // Only checked for halves whose walks are not disabled by EPD0 or EPD1.
+    const TCR_TSZ_MIN: u64 = 16;
+    const TCR_TSZ_MAX: u64 = 24;
+    const TCR_EPD0: u64 = 1 << 7;
+    const TCR_EPD1: u64 = 1 << 23;
+
+    // Hardware updates of the access flag and of dirty state
+    const TCR_HA: u64 = 1 << 39;
+    const TCR_HD: u64 = 1 << 40;
+
+    /// Check whether `tcr` enables hardware updates of both the access flag and dirty state
+    pub fn tcr_ad_bits_hardware_managed(tcr: u64) -> bool {
+        tcr & (Self::TCR_HA | Self::TCR_HD) == Self::TCR_HA | Self::TCR_HD
+    }
+
+    /// Set the inner and outer cacheability of table walks for `table_kind` in `tcr` to
+    /// write-back if `cached`, and to non-cacheable otherwise
+    pub fn tcr_table_walk_cached(tcr: u64, table_kind: TableKind, cached: bool) -> u64 {
+        let shift = match table_kind {
+            TableKind::User => 8,    // IRGN0, ORGN0
+            TableKind::Kernel => 24, // IRGN1, ORGN1
+        };
+        let value = if cached { 0b0101 } else { 0b0000 };
+        (tcr & !(0b1111 << shift)) | value << shift
+    }
+
+    /// Check register values against the configuration the entry flags and table layout assume
+    pub fn features_valid(mair: u64, tcr: u64) -> bool {
+        let tsz_valid = |shift: u32, epd: u64| {
+            tcr & epd != 0
+                || (Self::TCR_TSZ_MIN..=Self::TCR_TSZ_MAX).contains(&(tcr >> shift & 0x3F))
+        };
+        mair & Self::MAIR_ATTR0_MASK == Self::MAIR_ATTR0_NORMAL
+            && tcr & Self::TCR_GRANULE_MASK == Self::TCR_GRANULE_4K
+            && tsz_valid(0, Self::TCR_EPD0)
+            && tsz_valid(16, Self::TCR_EPD1)
+    }
+
+    #[inline(always)]
+    unsafe fn mair() -> u64 {
+        unsafe {
+            let mair: u64;
+            asm!("mrs {0}, mair_el1", out(reg) mair);
+            mair
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn tcr() -> u64 {
+        unsafe {
+            let tcr: u64;
+            asm!("mrs {0}, tcr_el1", out(reg) tcr);
+            tcr
+        }
+    }
+
     /// Synchronize instruction cache after writing code to memory.
     /// On aarch64, instruction and data caches are not coherent.
     ///
@@ -37,7 +108,8 @@ impl Arch for AArch64Arch {
     const ENTRY_ADDRESS_WIDTH: usize = 40;
     const ENTRY_FLAG_DEFAULT_PAGE: usize = Self::ENTRY_FLAG_PRESENT
         | 1 << 1 // Page flag
-        | 1 << 10 // Access flag
+        | Self::ENTRY_FLAG_ACCESSED
+        | Self::ENTRY_FLAG_INNER_SHAREABLE
         | Self::ENTRY_FLAG_NO_GLOBAL;
     const ENTRY_FLAG_DEFAULT_TABLE: usize
         = Self::ENTRY_FLAG_PRESENT
@@ -56,13 +128,50 @@ impl Arch for AArch64Arch {
     const ENTRY_FLAG_GLOBAL: usize = 0;
     const ENTRY_FLAG_NO_GLOBAL: usize = 1 << 11;
     const ENTRY_FLAG_WRITE_COMBINING: usize = 0;
+    // Accessing a page with this flag clear raises an access flag fault
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 10;
+    // There is no dirty flag without hardware dirty state management, which is not enabled
+    const ENTRY_FLAG_DIRTY: usize = 0;
+    const ENTRY_FLAG_OUTER_SHAREABLE: usize = 0b10 << 8;
+    const ENTRY_FLAG_INNER_SHAREABLE: usize = 0b11 << 8;
+    // Block descriptors have the table/page flag clear
+    const ENTRY_FLAG_HUGE: usize = 0;
+    const ENTRY_FLAG_NO_HUGE: usize = 1 << 1;
+    const ENTRY_FLAG_FOREIGN: usize = 1 << 55;
+    const ENTRY_FLAG_EXEC_REQUESTED: usize = 1 << 56;
+    const ENTRY_FLAG_SWAP_TOKEN: usize = 1 << 57;
+
+    const MAX_HUGE_LEVEL: usize = 2; // 1 GiB blocks
 
     const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+    const TLB_INVALIDATION_BROADCAST: bool = true; // tlbi with the inner shareable variants
+    const HW_TRANSLATE: bool = true; // at s1e1r
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("AArch64Arch::init unimplemented");
     }
 
+    #[inline(always)]
+    unsafe fn enable_required_features() {
+        unsafe {
+            let mair = (Self::mair() & !Self::MAIR_ATTR0_MASK) | Self::MAIR_ATTR0_NORMAL;
+            // The region sizes are left alone, they have to be set up with the tables
+            let tcr = (Self::tcr() & !Self::TCR_GRANULE_MASK) | Self::TCR_GRANULE_4K;
+            asm!(
+                "msr mair_el1, {0}",
+                "msr tcr_el1, {1}",
+                "isb",
+                in(reg) mair,
+                in(reg) tcr
+            );
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn verify_features() -> bool {
+        unsafe { Self::features_valid(Self::mair(), Self::tcr()) }
+    }
+
     #[inline(always)]
     unsafe fn invalidate(address: VirtualAddress) {
         unsafe {
@@ -89,6 +198,73 @@ impl Arch for AArch64Arch {
         }
     }
 
+    #[inline(always)]
+    fn isync() {
+        unsafe {
+            asm!("isb", options(nostack, preserves_flags));
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate_icache(_virt: VirtualAddress, _size: usize) {
+        // The instruction cache may be tagged by virtual address, so stale lines for the range
+        // can exist in any core. Invalidating all of them avoids depending on the line size.
+        unsafe {
+            asm!(
+                "
+            dsb ish
+            ic ialluis
+            dsb ish
+            isb
+        "
+            );
+        }
+    }
+
+    #[inline(always)]
+    fn prefetch_table(phys: PhysicalAddress) {
+        unsafe {
+            let virt = Self::phys_to_virt(phys);
+            asm!("prfm pldl1keep, [{0}]", in(reg) virt.data(), options(nostack, preserves_flags));
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn hw_translate(virt: VirtualAddress) -> Option<PhysicalAddress> {
+        let par: u64;
+        unsafe {
+            asm!(
+                "at s1e1r, {0}",
+                "isb",
+                "mrs {1}, par_el1",
+                in(reg) virt.data(),
+                out(reg) par,
+                options(nostack, preserves_flags)
+            );
+        }
+        // PAR_EL1.F is set if the translation faulted
+        if par & 1 != 0 {
+            return None;
+        }
+        let page = par as usize & (Self::ENTRY_ADDRESS_MASK << Self::ENTRY_ADDRESS_SHIFT);
+        Some(PhysicalAddress::new(
+            page | (virt.data() & Self::PAGE_OFFSET_MASK),
+        ))
+    }
+
+    #[inline(always)]
+    fn current_exception_level() -> u8 {
+        let current_el: u64;
+        unsafe {
+            asm!(
+                "mrs {0}, CurrentEL",
+                out(reg) current_el,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        ((current_el >> 2) & 0b11) as u8
+    }
+
     #[inline(always)]
     unsafe fn table(table_kind: TableKind) -> PhysicalAddress {
         unsafe {
@@ -131,16 +307,49 @@ impl Arch for AArch64Arch {
         }
     }
 
+    #[inline(always)]
+    fn ad_bits_hardware_managed() -> bool {
+        unsafe { Self::tcr_ad_bits_hardware_managed(Self::tcr()) }
+    }
+
+    #[inline(always)]
+    unsafe fn set_table_walk_cached(table_kind: TableKind, cached: bool) {
+        unsafe {
+            let old = Self::tcr();
+            let tcr = Self::tcr_table_walk_cached(old, table_kind, cached);
+            if tcr != old {
+                asm!(
+                    "msr tcr_el1, {0}",
+                    "isb",
+                    in(reg) tcr
+                );
+                Self::invalidate_all();
+            }
+        }
+    }
+
     fn virt_is_valid(_address: VirtualAddress) -> bool {
         //TODO: what makes an address valid on aarch64?
         true
     }
+
+    #[inline(always)]
+    fn needs_break_before_make(old: usize, new: usize) -> bool {
+        // Changing the output address, block size or memory type of a live entry is unpredictable
+        let present = Self::ENTRY_FLAG_PRESENT;
+        let address = Self::ENTRY_ADDRESS_MASK << Self::ENTRY_ADDRESS_SHIFT;
+        old & present != 0
+            && new & present != 0
+            && (old ^ new) & (address | Self::BREAK_BEFORE_MAKE_MASK) != 0
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::AArch64Arch;
-    use crate::Arch;
+    use crate::{
+        Arch, PageEntry, PageFlags, PhysicalAddress, Shareability, TableKind, VirtualAddress,
+    };
 
     #[test]
     fn constants() {
@@ -159,5 +368,162 @@ mod tests {
         assert_eq!(AArch64Arch::ENTRY_FLAGS_MASK, 0xFFF0_0000_0000_0FFF);
 
         assert_eq!(AArch64Arch::PHYS_OFFSET, 0xFFFF_8000_0000_0000);
+        assert!(AArch64Arch::TLB_INVALIDATION_BROADCAST);
+    }
+
+    #[test]
+    fn table_index() {
+        let virt = VirtualAddress::new(0xFFFF_8012_3456_7000);
+        assert_eq!(AArch64Arch::table_index(virt, 3), 0x100);
+        assert_eq!(AArch64Arch::table_index(virt, 2), 0x048);
+        assert_eq!(AArch64Arch::table_index(virt, 1), 0x1A2);
+        assert_eq!(AArch64Arch::table_index(virt, 0), 0x167);
+        assert_eq!(AArch64Arch::table_index(VirtualAddress::new(0x0FFF), 0), 0);
+    }
+
+    #[test]
+    fn tcr_ad_bits_hardware_managed() {
+        let tcr = 0x0000_0005_B510_3510;
+        assert!(!AArch64Arch::tcr_ad_bits_hardware_managed(tcr));
+        assert!(!AArch64Arch::tcr_ad_bits_hardware_managed(tcr | 1 << 39));
+        assert!(AArch64Arch::tcr_ad_bits_hardware_managed(
+            tcr | 1 << 39 | 1 << 40
+        ));
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn isync() {
+        AArch64Arch::isync();
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn prefetch_table() {
+        // The physmap is not mapped in a user process, which a prefetch must not fault on
+        AArch64Arch::prefetch_table(PhysicalAddress::new(0x1234_5000));
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn current_exception_level() {
+        // CurrentEL is not readable at EL0, so this only runs in a kernel context
+        if cfg!(target_os = "none") {
+            assert!(AArch64Arch::current_exception_level() >= 1);
+        }
+    }
+
+    #[test]
+    fn tlb_flush_all_threshold() {
+        // The TLB size is not architecturally exposed
+        assert_eq!(AArch64Arch::tlb_entry_count(), None);
+        assert_eq!(
+            AArch64Arch::tlb_flush_all_threshold(),
+            AArch64Arch::TLB_FLUSH_ALL_THRESHOLD
+        );
+        assert_eq!(crate::arch::flush_all_threshold(None, 7), 7);
+        assert_eq!(crate::arch::flush_all_threshold(Some(1536), 7), 96);
+        assert_eq!(crate::arch::flush_all_threshold(Some(4), 7), 1);
+    }
+
+    #[test]
+    fn needs_break_before_make() {
+        let flags = PageFlags::<AArch64Arch>::new().write(true);
+        let entry = |phys: usize, flags: PageFlags<AArch64Arch>| {
+            PageEntry::<AArch64Arch>::new(phys, flags.data()).data()
+        };
+        let old = entry(0x1000, flags);
+
+        // Changing the output address
+        assert!(AArch64Arch::needs_break_before_make(
+            old,
+            entry(0x2000, flags)
+        ));
+        // Changing between page and block
+        assert!(AArch64Arch::needs_break_before_make(
+            old,
+            entry(0x1000, flags.huge(true))
+        ));
+        // Changing the memory type and shareability
+        assert!(AArch64Arch::needs_break_before_make(
+            old,
+            entry(0x1000, flags.custom_flag(1 << 2, true))
+        ));
+        assert!(AArch64Arch::needs_break_before_make(
+            old,
+            entry(0x1000, flags.shareability(Shareability::NonShareable))
+        ));
+
+        // Permission and access flag changes are benign
+        assert!(!AArch64Arch::needs_break_before_make(old, old));
+        assert!(!AArch64Arch::needs_break_before_make(
+            old,
+            entry(0x1000, flags.write(false).execute(true))
+        ));
+        assert!(!AArch64Arch::needs_break_before_make(
+            old,
+            entry(0x1000, flags.accessed(false))
+        ));
+        // Nothing to break when either entry is invalid
+        assert!(!AArch64Arch::needs_break_before_make(0, old));
+        assert!(!AArch64Arch::needs_break_before_make(old, 0));
+    }
+
+    #[test]
+    fn shareability() {
+        let sh = |flags: PageFlags<AArch64Arch>| (flags.data() >> 8) & 0b11;
+
+        assert_eq!(sh(PageFlags::new()), 0b11);
+        assert_eq!(
+            sh(PageFlags::new().shareability(Shareability::NonShareable)),
+            0b00
+        );
+        assert_eq!(
+            sh(PageFlags::new().shareability(Shareability::OuterShareable)),
+            0b10
+        );
+        assert_eq!(
+            sh(PageFlags::new()
+                .shareability(Shareability::NonShareable)
+                .shareability(Shareability::InnerShareable)),
+            0b11
+        );
+    }
+
+    #[test]
+    fn features_valid() {
+        // IPS = 48 bits in the upper half is not checked
+        let tcr = 0x0000_0005_B510_3510;
+        assert!(AArch64Arch::features_valid(0x0000_0000_0044_04FF, tcr));
+
+        // Device memory in attribute 0
+        assert!(!AArch64Arch::features_valid(0x0000_0000_0044_0400, tcr));
+        // 64 KiB TTBR0 granule
+        assert!(!AArch64Arch::features_valid(0xFF, tcr | 0b01 << 14));
+        // 39-bit TTBR1 address space
+        let small = (tcr & !(0x3F << 16)) | 25 << 16;
+        assert!(!AArch64Arch::features_valid(0xFF, small));
+
+        // Fields the flags do not depend on: a 40-bit TTBR0 address space, uncached walks, the
+        // ASID from TTBR1 and top byte ignore
+        let tcr_other = (tcr & !0x3F & !(0b1111 << 8)) | 24 | 1 << 22 | 0b11 << 37;
+        assert!(AArch64Arch::features_valid(0xFF, tcr_other));
+        // Disabled TTBR1 walks, whose region size does not matter then
+        assert!(AArch64Arch::features_valid(0xFF, small | 1 << 23));
+    }
+
+    #[test]
+    fn tcr_table_walk_cached() {
+        let tcr = 0x0000_0005_B510_3510;
+        let user = AArch64Arch::tcr_table_walk_cached(tcr, TableKind::User, false);
+        assert_eq!(user, 0x0000_0005_B510_3010);
+        let both = AArch64Arch::tcr_table_walk_cached(user, TableKind::Kernel, false);
+        assert_eq!(both, 0x0000_0005_B010_3010);
+        // The flags do not depend on the cacheability of walks
+        assert!(AArch64Arch::features_valid(0xFF, both));
+
+        let user = AArch64Arch::tcr_table_walk_cached(both, TableKind::User, true);
+        let kernel = AArch64Arch::tcr_table_walk_cached(user, TableKind::Kernel, true);
+        assert_eq!(kernel, tcr);
     }
 }
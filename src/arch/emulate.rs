@@ -1,9 +1,11 @@
-use std::{collections::BTreeMap, marker::PhantomData, mem, ptr, sync::Mutex};
+use std::{cell::RefCell, collections::BTreeMap, marker::PhantomData, mem, ptr};
 
 use crate::{
     arch::x86_64::X8664Arch, page::PageFlags, Arch, MemoryArea, PageEntry, PhysicalAddress,
     TableKind, VirtualAddress, MEGABYTE,
 };
+#[cfg(test)]
+use crate::{BuddyAllocator, BumpAllocator, FrameAllocator, PageMapper};
 
 #[derive(Clone, Copy)]
 pub struct EmulateArch;
@@ -23,7 +25,12 @@ impl Arch for EmulateArch {
     const ENTRY_FLAG_NO_EXEC: usize = X8664Arch::ENTRY_FLAG_NO_EXEC;
     const ENTRY_FLAG_EXEC: usize = X8664Arch::ENTRY_FLAG_EXEC;
 
+    const MAX_HUGE_LEVEL: usize = X8664Arch::MAX_HUGE_LEVEL;
+
     const PHYS_OFFSET: usize = X8664Arch::PHYS_OFFSET;
+    const PHYS_MAP_SIZE: usize = X8664Arch::PHYS_MAP_SIZE;
+    const TLB_INVALIDATION_BROADCAST: bool = X8664Arch::TLB_INVALIDATION_BROADCAST;
+    const HW_TRANSLATE: bool = true;
 
     const ENTRY_FLAG_GLOBAL: usize = X8664Arch::ENTRY_FLAG_GLOBAL;
     const ENTRY_FLAG_NO_GLOBAL: usize = X8664Arch::ENTRY_FLAG_NO_GLOBAL;
@@ -31,6 +38,12 @@ impl Arch for EmulateArch {
     const ENTRY_ADDRESS_WIDTH: usize = X8664Arch::ENTRY_ADDRESS_WIDTH;
 
     const ENTRY_FLAG_WRITE_COMBINING: usize = X8664Arch::ENTRY_FLAG_WRITE_COMBINING;
+    const ENTRY_FLAG_ACCESSED: usize = X8664Arch::ENTRY_FLAG_ACCESSED;
+    const ENTRY_FLAG_DIRTY: usize = X8664Arch::ENTRY_FLAG_DIRTY;
+    const ENTRY_FLAG_HUGE: usize = X8664Arch::ENTRY_FLAG_HUGE;
+    const ENTRY_FLAG_FOREIGN: usize = X8664Arch::ENTRY_FLAG_FOREIGN;
+    const ENTRY_FLAG_EXEC_REQUESTED: usize = X8664Arch::ENTRY_FLAG_EXEC_REQUESTED;
+    const ENTRY_FLAG_SWAP_TOKEN: usize = X8664Arch::ENTRY_FLAG_SWAP_TOKEN;
 
     unsafe fn init() -> &'static [MemoryArea] {
         unsafe {
@@ -63,7 +76,7 @@ impl Arch for EmulateArch {
                 );
             }
 
-            *MACHINE.lock().unwrap() = Some(machine);
+            MACHINE.with(|m| *m.borrow_mut() = Some(machine));
 
             // Set table to pml4
             EmulateArch::set_table(TableKind::Kernel, PhysicalAddress::new(pml4));
@@ -74,52 +87,100 @@ impl Arch for EmulateArch {
 
     #[inline(always)]
     unsafe fn read<T>(address: VirtualAddress) -> T {
-        MACHINE.lock().unwrap().as_ref().unwrap().read(address)
+        with_machine(|machine| machine.read(address))
     }
 
     #[inline(always)]
     unsafe fn write<T>(address: VirtualAddress, value: T) {
-        MACHINE
-            .lock()
-            .unwrap()
-            .as_mut()
-            .unwrap()
-            .write(address, value)
+        with_machine(|machine| machine.write(address, value))
+    }
+
+    #[inline(always)]
+    unsafe fn compare_exchange(
+        address: VirtualAddress,
+        current: usize,
+        new: usize,
+    ) -> Result<usize, usize> {
+        // The machine is only used by the current thread
+        with_machine(|machine| {
+            let value = machine.read::<usize>(address);
+            if value == current {
+                machine.write(address, new);
+                Ok(value)
+            } else {
+                Err(value)
+            }
+        })
+    }
+
+    #[inline(always)]
+    unsafe fn write_slice(address: VirtualAddress, src: &[u8]) {
+        with_machine(|machine| {
+            for (i, &byte) in src.iter().enumerate() {
+                machine.write(address.add(i), byte);
+            }
+        })
     }
 
     #[inline(always)]
     unsafe fn write_bytes(address: VirtualAddress, value: u8, count: usize) {
-        MACHINE
-            .lock()
-            .unwrap()
-            .as_mut()
-            .unwrap()
-            .write_bytes(address, value, count)
+        with_machine(|machine| machine.write_bytes(address, value, count))
     }
 
     #[inline(always)]
     unsafe fn invalidate(address: VirtualAddress) {
-        MACHINE
-            .lock()
-            .unwrap()
-            .as_mut()
-            .unwrap()
-            .invalidate(address);
+        with_machine(|machine| machine.invalidate(address))
     }
 
     #[inline(always)]
     unsafe fn invalidate_all() {
-        MACHINE.lock().unwrap().as_mut().unwrap().invalidate_all();
+        with_machine(|machine| machine.invalidate_all())
+    }
+
+    #[inline(always)]
+    fn isync() {
+        // Emulated code is never fetched, ordering memory accesses is enough
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    unsafe fn hw_translate(virt: VirtualAddress) -> Option<PhysicalAddress> {
+        // The translations of the machine act as its TLB
+        with_machine(|machine| machine.translate(virt).map(|(phys, _)| phys))
+    }
+
+    #[inline(always)]
+    fn ad_bits_hardware_managed() -> bool {
+        with_machine(|machine| machine.ad_bits_hardware_managed)
+    }
+
+    #[inline(always)]
+    fn needs_break_before_make(old: usize, new: usize) -> bool {
+        // Like on aarch64, changing the address of a live entry needs it
+        with_machine(|machine| machine.break_before_make)
+            && (old ^ new) & !Self::ENTRY_FLAGS_MASK != 0
+    }
+
+    #[inline(always)]
+    fn current_exception_level() -> u8 {
+        // Code using the emulated machine acts as its kernel
+        0
     }
 
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
-        MACHINE.lock().unwrap().as_mut().unwrap().get_table()
+        with_machine(|machine| machine.get_table())
     }
 
     #[inline(always)]
     unsafe fn set_table(_table_kind: TableKind, address: PhysicalAddress) {
-        MACHINE.lock().unwrap().as_mut().unwrap().set_table(address);
+        with_machine(|machine| machine.set_table(address))
+    }
+
+    #[inline(always)]
+    unsafe fn set_table_walk_cached(table_kind: TableKind, cached: bool) {
+        // Only recorded, table walks of the machine have no caches to bypass
+        with_machine(|machine| machine.table_walk_cached[table_kind as usize] = cached)
     }
     fn virt_is_valid(_address: VirtualAddress) -> bool {
         // TODO: Don't see why an emulated arch would have any problems with canonicalness...
@@ -140,12 +201,42 @@ static MEMORY_AREAS: [MemoryArea; 2] = [
     },
 ];
 
-static MACHINE: Mutex<Option<Machine<EmulateArch>>> = Mutex::new(None);
+// Every thread gets its own machine, so tests running in parallel do not share memory
+thread_local! {
+    static MACHINE: RefCell<Option<Machine<EmulateArch>>> = const { RefCell::new(None) };
+}
+
+/// Memory access of the emulated machine, recorded in order, see
+/// [`EmulateArch::record_events`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum MachineEvent {
+    Write(PhysicalAddress),
+    Invalidate(VirtualAddress),
+}
+
+fn with_machine<T>(f: impl FnOnce(&mut Machine<EmulateArch>) -> T) -> T {
+    MACHINE.with(|machine| {
+        f(machine
+            .borrow_mut()
+            .as_mut()
+            .expect("EmulateArch::init not called on this thread"))
+    })
+}
 
 struct Machine<A> {
     memory: Box<[u8]>,
     map: BTreeMap<VirtualAddress, PageEntry<A>>,
     table_addr: PhysicalAddress,
+    // Last configuration set for table walks of each table kind
+    table_walk_cached: [bool; 2],
+    // Number of single page and full invalidations so far
+    invalidations: usize,
+    // Only reported, the machine never updates accessed and dirty flags
+    ad_bits_hardware_managed: bool,
+    // Only reported, stale translations stay in the map until invalidated either way
+    break_before_make: bool,
+    // Writes and invalidations so far, while recording
+    events: Option<Vec<MachineEvent>>,
     phantom: PhantomData<A>,
 }
 
@@ -155,6 +246,11 @@ impl<A: Arch> Machine<A> {
             memory: vec![0; memory_size].into_boxed_slice(),
             map: BTreeMap::new(),
             table_addr: PhysicalAddress::new(0),
+            table_walk_cached: [true; 2],
+            invalidations: 0,
+            ad_bits_hardware_managed: true,
+            break_before_make: false,
+            events: None,
             phantom: PhantomData,
         }
     }
@@ -240,6 +336,7 @@ impl<A: Arch> Machine<A> {
 
         if let Some((phys, flags)) = self.translate(virt) {
             if flags.has_write() {
+                self.record(MachineEvent::Write(phys));
                 self.write_phys(phys, value);
             } else {
                 panic!("write: 0x{:X} size 0x{:X} not writable", virt_data, size);
@@ -262,6 +359,7 @@ impl<A: Arch> Machine<A> {
 
         if let Some((phys, flags)) = self.translate(virt) {
             if flags.has_write() {
+                self.record(MachineEvent::Write(phys));
                 self.write_phys_bytes(phys, value, count);
             } else {
                 panic!(
@@ -277,61 +375,74 @@ impl<A: Arch> Machine<A> {
         }
     }
 
-    fn invalidate(&mut self, _address: VirtualAddress) {
-        unimplemented!("EmulateArch::invalidate not implemented");
+    fn walk(&self, virt: VirtualAddress) -> Option<PageEntry<A>> {
+        let mut table = self.table_addr;
+        for level in (0..A::PAGE_LEVELS).rev() {
+            let shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+            let i = A::table_index(virt, level);
+            let entry = PageEntry::<A>::from_data(
+                self.read_phys::<usize>(table.add(i * A::PAGE_ENTRY_SIZE)),
+            );
+            if !entry.present() {
+                return None;
+            }
+            if level == 0 || A::entry_is_huge(entry.data(), level) {
+                // Split huge pages into the base page containing virt
+                let offset = virt.data() & ((1 << shift) - 1) & A::PAGE_ADDRESS_MASK;
+                return Some(PageEntry::new(
+                    entry.address().ok()?.add(offset).data(),
+                    entry.flags().data(),
+                ));
+            }
+            table = entry.address().ok()?;
+        }
+        None
+    }
+
+    fn invalidate(&mut self, address: VirtualAddress) {
+        let page = VirtualAddress::new(address.data() & A::PAGE_ADDRESS_MASK);
+        self.invalidations += 1;
+        self.record(MachineEvent::Invalidate(VirtualAddress::new(
+            address.data() & !A::PAGE_OFFSET_MASK,
+        )));
+        self.map.remove(&page);
+        if let Some(entry) = self.walk(page) {
+            self.map.insert(page, entry);
+        }
+    }
+
+    fn record(&mut self, event: MachineEvent) {
+        if let Some(events) = &mut self.events {
+            events.push(event);
+        }
     }
 
-    //TODO: cleanup
     fn invalidate_all(&mut self) {
+        self.invalidations += 1;
         self.map.clear();
+        self.fill_map(self.table_addr, A::PAGE_LEVELS - 1, 0);
+    }
 
-        // PML4
-        let a4 = self.table_addr.data();
-        for i4 in 0..A::PAGE_ENTRIES {
-            let e3 = self.read_phys::<usize>(PhysicalAddress::new(a4 + i4 * A::PAGE_ENTRY_SIZE));
-            let f3 = e3 & A::ENTRY_FLAGS_MASK;
-            if f3 & A::ENTRY_FLAG_PRESENT == 0 {
+    fn fill_map(&mut self, table: PhysicalAddress, level: usize, base: usize) {
+        let shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+        for i in 0..A::PAGE_ENTRIES {
+            let entry = PageEntry::<A>::from_data(
+                self.read_phys::<usize>(table.add(i * A::PAGE_ENTRY_SIZE)),
+            );
+            let Ok(address) = entry.address() else {
                 continue;
-            }
-
-            // Page directory pointer
-            let a3 = ((e3 >> A::ENTRY_ADDRESS_SHIFT) & A::ENTRY_ADDRESS_MASK) << A::PAGE_SHIFT;
-            for i3 in 0..A::PAGE_ENTRIES {
-                let e2 =
-                    self.read_phys::<usize>(PhysicalAddress::new(a3 + i3 * A::PAGE_ENTRY_SIZE));
-                let f2 = e2 & A::ENTRY_FLAGS_MASK;
-                if f2 & A::ENTRY_FLAG_PRESENT == 0 {
-                    continue;
-                }
-
-                // Page directory
-                let a2 = ((e2 >> A::ENTRY_ADDRESS_SHIFT) & A::ENTRY_ADDRESS_MASK) << A::PAGE_SHIFT;
-                for i2 in 0..A::PAGE_ENTRIES {
-                    let e1 =
-                        self.read_phys::<usize>(PhysicalAddress::new(a2 + i2 * A::PAGE_ENTRY_SIZE));
-                    let f1 = e1 & A::ENTRY_FLAGS_MASK;
-                    if f1 & A::ENTRY_FLAG_PRESENT == 0 {
-                        continue;
-                    }
-
-                    // Page table
-                    let a1 =
-                        ((e1 >> A::ENTRY_ADDRESS_SHIFT) & A::ENTRY_ADDRESS_MASK) << A::PAGE_SHIFT;
-                    for i1 in 0..A::PAGE_ENTRIES {
-                        let e = self
-                            .read_phys::<usize>(PhysicalAddress::new(a1 + i1 * A::PAGE_ENTRY_SIZE));
-                        let f = e & A::ENTRY_FLAGS_MASK;
-                        if f & A::ENTRY_FLAG_PRESENT == 0 {
-                            continue;
-                        }
-
-                        // Page
-                        let page = (i4 << 39) | (i3 << 30) | (i2 << 21) | (i1 << 12);
-                        //println!("map 0x{:X} to 0x{:X}, 0x{:X}", page, a, f);
-                        self.map
-                            .insert(VirtualAddress::new(page), PageEntry::from_data(e));
-                    }
+            };
+
+            let virt = base | (i << shift);
+            if level == 0 || A::entry_is_huge(entry.data(), level) {
+                for offset in (0..1 << shift).step_by(A::PAGE_SIZE) {
+                    self.map.insert(
+                        VirtualAddress::new(virt + offset),
+                        PageEntry::new(address.add(offset).data(), entry.flags().data()),
+                    );
                 }
+            } else {
+                self.fill_map(address, level - 1, virt);
             }
         }
     }
@@ -341,7 +452,74 @@ impl<A: Arch> Machine<A> {
     }
 
     fn set_table(&mut self, address: PhysicalAddress) {
+        // Like reloading CR3 with CR4.PGE set, global translations survive switching tables
+        let global: Vec<_> = self
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.flags().is_global())
+            .map(|(&page, &entry)| (page, entry))
+            .collect();
         self.table_addr = address;
         self.invalidate_all();
+        self.map.extend(global);
+    }
+}
+
+#[cfg(test)]
+impl EmulateArch {
+    /// Initialize the machine of the current thread with all of its memory mapped at
+    /// `PHYS_OFFSET`, returning an allocator over the frames that are left.
+    pub(crate) unsafe fn init_test() -> BuddyAllocator<Self> {
+        unsafe {
+            let areas = Self::init();
+            let mut bump_allocator = BumpAllocator::<Self>::new(areas, 0);
+            {
+                let mut mapper =
+                    PageMapper::<Self, _>::create(TableKind::Kernel, &mut bump_allocator)
+                        .expect("failed to create mapper");
+                for area in areas.iter() {
+                    for i in 0..area.size / Self::PAGE_SIZE {
+                        let phys = area.base.add(i * Self::PAGE_SIZE);
+                        let virt = Self::phys_to_virt(phys);
+                        mapper
+                            .map_phys(virt, phys, PageFlags::<Self>::new().write(true))
+                            .expect("failed to map physmap")
+                            .ignore();
+                    }
+                }
+                mapper.make_current();
+            }
+            BuddyAllocator::new(bump_allocator).expect("failed to create allocator")
+        }
+    }
+
+    /// Check whether table walks for `table_kind` were last configured to be cached
+    pub(crate) fn table_walk_cached(table_kind: TableKind) -> bool {
+        with_machine(|machine| machine.table_walk_cached[table_kind as usize])
+    }
+
+    /// Set the mode reported by [`Arch::ad_bits_hardware_managed`]
+    pub(crate) fn set_ad_bits_hardware_managed(managed: bool) {
+        with_machine(|machine| machine.ad_bits_hardware_managed = managed)
+    }
+
+    /// Number of TLB invalidations so far, counting a full invalidation as one
+    pub(crate) fn invalidations() -> usize {
+        with_machine(|machine| machine.invalidations)
+    }
+
+    /// Set whether [`Arch::needs_break_before_make`] reports address changes as needing it
+    pub(crate) fn set_break_before_make(needed: bool) {
+        with_machine(|machine| machine.break_before_make = needed)
+    }
+
+    /// Start recording writes and single page invalidations, dropping those recorded before
+    pub(crate) fn record_events() {
+        with_machine(|machine| machine.events = Some(Vec::new()))
+    }
+
+    /// Stop recording, returning the events recorded in order
+    pub(crate) fn take_events() -> Vec<MachineEvent> {
+        with_machine(|machine| machine.events.take().unwrap_or_default())
     }
 }
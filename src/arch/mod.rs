@@ -1,10 +1,15 @@
-use core::ptr;
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
 
 //TODO: Support having all page tables compile on all architectures
 #[cfg(all(feature = "std", target_pointer_width = "64"))]
 pub use self::emulate::EmulateArch;
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+pub(crate) use self::emulate::MachineEvent;
 #[cfg(target_pointer_width = "32")]
 pub use self::x86::X86Arch;
 #[cfg(target_pointer_width = "64")]
@@ -25,6 +30,12 @@ mod x86;
 #[cfg(target_pointer_width = "64")]
 mod x86_64;
 
+/// Number of pages above which flushing the entire TLB is expected to be cheaper than flushing
+/// each page, for a TLB with `entries` base page entries, or `fallback` if the size is unknown
+pub fn flush_all_threshold(entries: Option<usize>, fallback: usize) -> usize {
+    entries.map_or(fallback, |entries| (entries / 16).max(1))
+}
+
 pub trait Arch: Clone + Copy {
     const PAGE_SHIFT: usize;
     const PAGE_ENTRY_SHIFT: usize;
@@ -44,9 +55,30 @@ pub trait Arch: Clone + Copy {
     const ENTRY_FLAG_GLOBAL: usize;
     const ENTRY_FLAG_NO_GLOBAL: usize;
     const ENTRY_FLAG_WRITE_COMBINING: usize;
+    const ENTRY_FLAG_ACCESSED: usize; // Set on first access, by hardware or by a fault handler
+    const ENTRY_FLAG_DIRTY: usize; // Set on first write, by hardware or by a fault handler
+    const ENTRY_FLAG_OUTER_SHAREABLE: usize = 0; // Non-shareable when neither is set
+    const ENTRY_FLAG_INNER_SHAREABLE: usize = 0;
+    const ENTRY_FLAG_HUGE: usize = 0; // Directory entry maps a huge page
+    const ENTRY_FLAG_NO_HUGE: usize = 0; // Directory entry points to a table
+    const ENTRY_FLAG_FOREIGN: usize; // Software bit, frame was not allocated by the mapper
+    const ENTRY_FLAG_EXEC_REQUESTED: usize; // Software bit, set by PageFlags::execute
+    const ENTRY_FLAG_SWAP_TOKEN: usize; // Software bit, non-present entry holds a swap token
 
     const PHYS_OFFSET: usize;
 
+    // Highest table level that can map huge pages, 0 if huge pages are not supported
+    const MAX_HUGE_LEVEL: usize = 0;
+
+    // TLB invalidation reaches all cores without software shootdown, so no IPIs are needed
+    const TLB_INVALIDATION_BROADCAST: bool;
+    // Pages above which invalidate_range flushes the entire TLB, if the TLB size is unknown
+    const TLB_FLUSH_ALL_THRESHOLD: usize = 32;
+    // Size of the virtual window at PHYS_OFFSET that physical memory is mapped into
+    const PHYS_MAP_SIZE: usize = 0usize.wrapping_sub(Self::PHYS_OFFSET);
+    // The MMU can translate addresses on request, see hw_translate
+    const HW_TRANSLATE: bool = false;
+
     const PAGE_SIZE: usize = 1 << Self::PAGE_SHIFT;
     const PAGE_OFFSET_MASK: usize = Self::PAGE_SIZE - 1;
     const PAGE_ADDRESS_SHIFT: usize = Self::PAGE_LEVELS * Self::PAGE_ENTRY_SHIFT + Self::PAGE_SHIFT;
@@ -63,6 +95,19 @@ pub trait Arch: Clone + Copy {
 
     unsafe fn init() -> &'static [MemoryArea];
 
+    /// Program the control registers to the configuration the entry flags of this architecture
+    /// assume, such as no-execute support and memory attributes. Must be called before any table
+    /// built by this crate is made current.
+    #[inline(always)]
+    unsafe fn enable_required_features() {}
+
+    /// Check that the control registers match the configuration set by
+    /// `enable_required_features`
+    #[inline(always)]
+    unsafe fn verify_features() -> bool {
+        true
+    }
+
     #[inline(always)]
     unsafe fn read<T>(address: VirtualAddress) -> T {
         unsafe { ptr::read(address.data() as *const T) }
@@ -73,6 +118,29 @@ pub trait Arch: Clone + Copy {
         unsafe { ptr::write(address.data() as *mut T, value) }
     }
 
+    /// Atomically replace the word at `address` with `new` if it is still `current`, returning
+    /// the previous value, or the value found instead of `current` on failure
+    #[inline(always)]
+    unsafe fn compare_exchange(
+        address: VirtualAddress,
+        current: usize,
+        new: usize,
+    ) -> Result<usize, usize> {
+        unsafe {
+            AtomicUsize::from_ptr(address.data() as *mut usize).compare_exchange(
+                current,
+                new,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn write_slice(address: VirtualAddress, src: &[u8]) {
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr(), address.data() as *mut u8, src.len()) }
+    }
+
     #[inline(always)]
     unsafe fn write_bytes(address: VirtualAddress, value: u8, count: usize) {
         unsafe { ptr::write_bytes(address.data() as *mut u8, value, count) }
@@ -80,6 +148,32 @@ pub trait Arch: Clone + Copy {
 
     unsafe fn invalidate(address: VirtualAddress);
 
+    /// Invalidate `count` pages starting at `address`, or the entire TLB if that is expected to
+    /// be cheaper
+    #[inline(always)]
+    unsafe fn invalidate_range(address: VirtualAddress, count: usize) {
+        unsafe {
+            if count > Self::tlb_flush_all_threshold() {
+                Self::invalidate_all();
+            } else {
+                for i in 0..count {
+                    Self::invalidate(address.add(i * Self::PAGE_SIZE));
+                }
+            }
+        }
+    }
+
+    /// Best-effort number of TLB entries for base pages, `None` if unknown
+    #[inline(always)]
+    fn tlb_entry_count() -> Option<usize> {
+        None
+    }
+
+    #[inline(always)]
+    fn tlb_flush_all_threshold() -> usize {
+        flush_all_threshold(Self::tlb_entry_count(), Self::TLB_FLUSH_ALL_THRESHOLD)
+    }
+
     #[inline(always)]
     unsafe fn invalidate_all() {
         unsafe {
@@ -88,10 +182,58 @@ pub trait Arch: Clone + Copy {
         }
     }
 
+    /// Synchronize the instruction stream, so that instructions after this one are fetched again
+    /// and see earlier changes to code or to system registers that affect execution
+    fn isync();
+
+    /// Invalidate the instruction cache for the `size` bytes at `virt`, such as after code was
+    /// moved to a new virtual address. The instruction caches of most architectures are coherent,
+    /// so only the instruction stream is synchronized by default.
+    #[inline(always)]
+    unsafe fn invalidate_icache(_virt: VirtualAddress, _size: usize) {
+        Self::isync();
+    }
+
+    /// Hint that the table at `phys` is about to be walked, so that its first cache line can be
+    /// loaded through the physmap in the meantime. Prefetches never fault, so any address is fine.
+    #[inline(always)]
+    fn prefetch_table(_phys: PhysicalAddress) {}
+
+    /// Whether the MMU sets the accessed and dirty flags of entries itself. Otherwise, the first
+    /// access and write to a page fault instead, and have to be tracked by the fault handler,
+    /// see [`crate::AccessTracking`].
+    #[inline(always)]
+    fn ad_bits_hardware_managed() -> bool {
+        true
+    }
+
+    /// Privilege level the code is running at, the exception level on aarch64 and the ring on
+    /// x86. Kernel code runs at 1 on aarch64 and 0 on x86, while hosted tests run at 0 and 3.
+    fn current_exception_level() -> u8;
+
     unsafe fn table(table_kind: TableKind) -> PhysicalAddress;
 
     unsafe fn set_table(table_kind: TableKind, address: PhysicalAddress);
 
+    /// Configure whether the MMU reads the tables of `table_kind` through the caches, where that
+    /// can be controlled. Ignored by default.
+    #[inline(always)]
+    unsafe fn set_table_walk_cached(_table_kind: TableKind, _cached: bool) {}
+
+    /// Translate `virt` the way the MMU does for the current tables, including any stale TLB
+    /// entries. Returns `None` if the translation faults, or if `HW_TRANSLATE` is not set.
+    #[inline(always)]
+    unsafe fn hw_translate(_virt: VirtualAddress) -> Option<PhysicalAddress> {
+        None
+    }
+
+    /// Index of the entry covering `virt` in a table of `level`, where level 0 maps base pages
+    #[inline(always)]
+    fn table_index(virt: VirtualAddress, level: usize) -> usize {
+        debug_assert!(level < Self::PAGE_LEVELS);
+        (virt.data() >> (level * Self::PAGE_ENTRY_SHIFT + Self::PAGE_SHIFT)) & Self::PAGE_ENTRY_MASK
+    }
+
     #[inline(always)]
     unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
         match phys.data().checked_add(Self::PHYS_OFFSET) {
@@ -101,4 +243,33 @@ pub trait Arch: Clone + Copy {
     }
 
     fn virt_is_valid(address: VirtualAddress) -> bool;
+
+    /// Check if replacing the live entry `old` with `new` requires break-before-make: writing an
+    /// invalid entry and invalidating the TLB before writing `new`
+    #[inline(always)]
+    fn needs_break_before_make(_old: usize, _new: usize) -> bool {
+        false
+    }
+
+    /// Check if the present entry `data` in a table of `level` maps a huge page instead of
+    /// pointing to the next table. Level 0 entries are never huge.
+    #[inline(always)]
+    fn entry_is_huge(data: usize, level: usize) -> bool {
+        let mask = Self::ENTRY_FLAG_HUGE | Self::ENTRY_FLAG_NO_HUGE;
+        level > 0
+            && mask != 0
+            && data & Self::ENTRY_FLAG_PRESENT != 0
+            && data & mask == Self::ENTRY_FLAG_HUGE
+    }
+
+    /// Check that every area lies within the physmap window, so that `phys_to_virt` is valid for
+    /// all of their frames.
+    fn physmap_covers(areas: &[MemoryArea]) -> bool {
+        areas.iter().all(|area| {
+            area.base
+                .data()
+                .checked_add(area.size)
+                .is_some_and(|end| end <= Self::PHYS_MAP_SIZE)
+        })
+    }
 }
@@ -29,8 +29,16 @@ impl Arch for RiscV64Sv39Arch {
     const ENTRY_FLAG_GLOBAL: usize = 1 << 5;
     const ENTRY_FLAG_NO_GLOBAL: usize = 0;
     const ENTRY_FLAG_WRITE_COMBINING: usize = 0;
+    const ENTRY_FLAG_ACCESSED: usize = ACCESSED;
+    const ENTRY_FLAG_DIRTY: usize = DIRTY;
+    const ENTRY_FLAG_FOREIGN: usize = 1 << 8; // RSW
+    const ENTRY_FLAG_EXEC_REQUESTED: usize = 1 << 9; // RSW
+    const ENTRY_FLAG_SWAP_TOKEN: usize = 1 << 60; // Reserved, never set in valid entries
+
+    const MAX_HUGE_LEVEL: usize = 2; // 1 GiB gigapages
 
     const PHYS_OFFSET: usize = 0xFFFF_FFC0_0000_0000;
+    const TLB_INVALIDATION_BROADCAST: bool = false; // sfence.vma only affects the local hart
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("RiscV64Sv39Arch::init unimplemented");
@@ -50,6 +58,25 @@ impl Arch for RiscV64Sv39Arch {
         }
     }
 
+    #[inline(always)]
+    fn isync() {
+        unsafe {
+            asm!("fence.i");
+        }
+    }
+
+    #[inline(always)]
+    fn ad_bits_hardware_managed() -> bool {
+        // Without Svadu, accesses to pages with the accessed or dirty flag clear fault
+        false
+    }
+
+    #[inline(always)]
+    fn current_exception_level() -> u8 {
+        // The privilege mode can not be read, and this crate is only used by S-mode kernels
+        1
+    }
+
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
         unsafe {
@@ -77,6 +104,14 @@ impl Arch for RiscV64Sv39Arch {
 
         masked == mask || masked == 0
     }
+
+    #[inline(always)]
+    fn entry_is_huge(data: usize, level: usize) -> bool {
+        // Any entry with read, write or execute permission is a leaf
+        level > 0
+            && data & Self::ENTRY_FLAG_PRESENT != 0
+            && data & (Self::ENTRY_FLAG_READWRITE | Self::ENTRY_FLAG_EXEC) != 0
+    }
 }
 
 #[cfg(test)]
@@ -101,6 +136,25 @@ mod tests {
         assert_eq!(RiscV64Sv39Arch::ENTRY_FLAGS_MASK, 0xFFC0_0000_0000_03FF);
 
         assert_eq!(RiscV64Sv39Arch::PHYS_OFFSET, 0xFFFF_FFC0_0000_0000);
+        assert!(!RiscV64Sv39Arch::TLB_INVALIDATION_BROADCAST);
+    }
+
+    #[test]
+    fn table_index() {
+        use super::VirtualAddress;
+
+        let virt = VirtualAddress::new(0xFFFF_FFC0_8060_1000);
+        assert_eq!(RiscV64Sv39Arch::table_index(virt, 2), 0x102);
+        assert_eq!(RiscV64Sv39Arch::table_index(virt, 1), 0x003);
+        assert_eq!(RiscV64Sv39Arch::table_index(virt, 0), 0x001);
+        let top = VirtualAddress::new(0x0000_007F_FFFF_F000);
+        assert_eq!(RiscV64Sv39Arch::table_index(top, 2), 0x1FF);
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    #[test]
+    fn isync() {
+        RiscV64Sv39Arch::isync();
     }
     #[test]
     fn is_canonical() {
@@ -25,8 +25,16 @@ impl Arch for RiscV64Sv48Arch {
     const ENTRY_FLAG_GLOBAL: usize = 1 << 5;
     const ENTRY_FLAG_NO_GLOBAL: usize = 0;
     const ENTRY_FLAG_WRITE_COMBINING: usize = 0;
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 6;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 7;
+    const ENTRY_FLAG_FOREIGN: usize = 1 << 8; // RSW
+    const ENTRY_FLAG_EXEC_REQUESTED: usize = 1 << 9; // RSW
+    const ENTRY_FLAG_SWAP_TOKEN: usize = 1 << 60; // Reserved, never set in valid entries
+
+    const MAX_HUGE_LEVEL: usize = 3; // 512 GiB terapages
 
     const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+    const TLB_INVALIDATION_BROADCAST: bool = false; // sfence.vma only affects the local hart
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("RiscV64Sv48Arch::init unimplemented");
@@ -46,6 +54,25 @@ impl Arch for RiscV64Sv48Arch {
         }
     }
 
+    #[inline(always)]
+    fn isync() {
+        unsafe {
+            asm!("fence.i");
+        }
+    }
+
+    #[inline(always)]
+    fn ad_bits_hardware_managed() -> bool {
+        // Without Svadu, accesses to pages with the accessed or dirty flag clear fault
+        false
+    }
+
+    #[inline(always)]
+    fn current_exception_level() -> u8 {
+        // The privilege mode can not be read, and this crate is only used by S-mode kernels
+        1
+    }
+
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
         unsafe {
@@ -74,6 +101,14 @@ impl Arch for RiscV64Sv48Arch {
 
         masked == mask || masked == 0
     }
+
+    #[inline(always)]
+    fn entry_is_huge(data: usize, level: usize) -> bool {
+        // Any entry with read, write or execute permission is a leaf
+        level > 0
+            && data & Self::ENTRY_FLAG_PRESENT != 0
+            && data & (Self::ENTRY_FLAG_READWRITE | Self::ENTRY_FLAG_EXEC) != 0
+    }
 }
 
 #[cfg(test)]
@@ -98,6 +133,13 @@ mod tests {
         assert_eq!(RiscV64Sv48Arch::ENTRY_FLAGS_MASK, 0xFFC0_0000_0000_03FF);
 
         assert_eq!(RiscV64Sv48Arch::PHYS_OFFSET, 0xFFFF_8000_0000_0000);
+        assert!(!RiscV64Sv48Arch::TLB_INVALIDATION_BROADCAST);
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    #[test]
+    fn isync() {
+        RiscV64Sv48Arch::isync();
     }
     #[test]
     fn is_canonical() {
@@ -24,8 +24,14 @@ impl Arch for X86Arch {
     const ENTRY_FLAG_NO_EXEC: usize = 0; // NOT AVAILABLE UNLESS PAE IS USED!
     const ENTRY_FLAG_EXEC: usize = 0;
     const ENTRY_FLAG_WRITE_COMBINING: usize = 1 << 7;
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 5;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 6;
+    const ENTRY_FLAG_FOREIGN: usize = 1 << 9;
+    const ENTRY_FLAG_EXEC_REQUESTED: usize = 1 << 10;
+    const ENTRY_FLAG_SWAP_TOKEN: usize = 1 << 11;
 
     const PHYS_OFFSET: usize = 0x8000_0000;
+    const TLB_INVALIDATION_BROADCAST: bool = false; // invlpg only affects the local core
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("X86Arch::init unimplemented");
@@ -36,6 +42,32 @@ impl Arch for X86Arch {
         asm!("invlpg [{0}]", in(reg) address.data());
     }
 
+    #[inline(always)]
+    fn isync() {
+        // cpuid is a serializing instruction
+        unsafe {
+            core::arch::x86::__cpuid(0);
+        }
+    }
+
+    #[inline(always)]
+    fn prefetch_table(phys: PhysicalAddress) {
+        unsafe {
+            let virt = Self::phys_to_virt(phys);
+            asm!("prefetcht0 [{0}]", in(reg) virt.data(), options(nostack, preserves_flags));
+        }
+    }
+
+    #[inline(always)]
+    fn current_exception_level() -> u8 {
+        // Current privilege level is the RPL of the code segment selector
+        let cs: u16;
+        unsafe {
+            asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+        }
+        (cs & 0b11) as u8
+    }
+
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
         let address: usize;
@@ -76,5 +108,6 @@ mod tests {
         assert_eq!(X86Arch::ENTRY_FLAGS_MASK, 0x0000_0FFF);
 
         assert_eq!(X86Arch::PHYS_OFFSET, 0x8000_0000);
+        assert!(!X86Arch::TLB_INVALIDATION_BROADCAST);
     }
 }
@@ -1,10 +1,117 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{Arch, MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
 
 #[derive(Clone, Copy, Debug)]
 pub struct X8664Arch;
 
+impl X8664Arch {
+    const MSR_EFER: u32 = 0xC000_0080;
+    const MSR_PAT: u32 = 0x277;
+
+    // Required for ENTRY_FLAG_NO_EXEC
+    const EFER_NXE: u64 = 1 << 11;
+    // Required for ENTRY_FLAG_GLOBAL
+    const CR4_PGE: usize = 1 << 7;
+    const CR4_PAE: usize = 1 << 5;
+    // ENTRY_FLAG_WRITE_COMBINING selects PAT entry 4, which must be write combining
+    const PAT_ENTRY_4_MASK: u64 = 0xFF << 32;
+    const PAT_ENTRY_4_WRITE_COMBINING: u64 = 0x01 << 32;
+
+    /// Check register values against the configuration the entry flags assume
+    pub fn features_valid(efer: u64, cr4: usize, pat: u64) -> bool {
+        efer & Self::EFER_NXE != 0
+            && cr4 & (Self::CR4_PAE | Self::CR4_PGE) == Self::CR4_PAE | Self::CR4_PGE
+            && pat & Self::PAT_ENTRY_4_MASK == Self::PAT_ENTRY_4_WRITE_COMBINING
+    }
+
+    #[inline(always)]
+    unsafe fn rdmsr(msr: u32) -> u64 {
+        unsafe {
+            let low: u32;
+            let high: u32;
+            asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high);
+            ((high as u64) << 32) | (low as u64)
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn wrmsr(msr: u32, value: u64) {
+        unsafe {
+            asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32);
+        }
+    }
+
+    #[inline(always)]
+    fn cpuid(leaf: u32, subleaf: u32) -> [u32; 4] {
+        let eax: u32;
+        let ebx: u64;
+        let ecx: u32;
+        let edx: u32;
+        unsafe {
+            // rbx is reserved by LLVM and has to be preserved manually
+            asm!(
+                "mov {ebx}, rbx",
+                "cpuid",
+                "xchg {ebx}, rbx",
+                ebx = out(reg) ebx,
+                inout("eax") leaf => eax,
+                inout("ecx") subleaf => ecx,
+                out("edx") edx,
+            );
+        }
+        [eax, ebx as u32, ecx, edx]
+    }
+
+    fn probe_tlb_entry_count() -> Option<usize> {
+        // Intel: deterministic address translation parameters, largest data or unified TLB
+        // supporting 4 KiB pages
+        if Self::cpuid(0, 0)[0] >= 0x18 {
+            let max_subleaf = Self::cpuid(0x18, 0)[0];
+            let mut entries = 0;
+            for subleaf in 0..=max_subleaf {
+                let [_, ebx, ecx, edx] = Self::cpuid(0x18, subleaf);
+                let kind = edx & 0x1F;
+                if (kind == 1 || kind == 3) && ebx & 1 != 0 {
+                    entries = entries.max((ebx >> 16) as usize * ecx as usize);
+                }
+            }
+            if entries > 0 {
+                return Some(entries);
+            }
+        }
+
+        // AMD: L2 data TLB for 4 KiB pages, falling back to the L1 data TLB
+        let max_extended = Self::cpuid(0x8000_0000, 0)[0];
+        if max_extended >= 0x8000_0006 {
+            let entries = (Self::cpuid(0x8000_0006, 0)[1] >> 16) & 0xFFF;
+            if entries > 0 {
+                return Some(entries as usize);
+            }
+        }
+        if max_extended >= 0x8000_0005 {
+            let entries = (Self::cpuid(0x8000_0005, 0)[1] >> 16) & 0xFF;
+            if entries > 0 {
+                return Some(entries as usize);
+            }
+        }
+
+        None
+    }
+
+    #[inline(always)]
+    unsafe fn cr4() -> usize {
+        unsafe {
+            let cr4: usize;
+            asm!("mov {0}, cr4", out(reg) cr4);
+            cr4
+        }
+    }
+}
+
 impl Arch for X8664Arch {
     const PAGE_SHIFT: usize = 12; // 4096 bytes
     const PAGE_ENTRY_SHIFT: usize = 9; // 512 entries, 8 bytes each
@@ -17,19 +124,55 @@ impl Arch for X8664Arch {
     const ENTRY_FLAG_READONLY: usize = 0;
     const ENTRY_FLAG_READWRITE: usize = 1 << 1;
     const ENTRY_FLAG_PAGE_USER: usize = 1 << 2;
-    // Not used: const ENTRY_FLAG_HUGE: usize = 1 << 7;
+    const ENTRY_FLAG_HUGE: usize = 1 << 7;
     const ENTRY_FLAG_GLOBAL: usize = 1 << 8;
     const ENTRY_FLAG_NO_GLOBAL: usize = 0;
     const ENTRY_FLAG_NO_EXEC: usize = 1 << 63;
     const ENTRY_FLAG_EXEC: usize = 0;
     const ENTRY_FLAG_WRITE_COMBINING: usize = 1 << 7;
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 5;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 6;
+    const ENTRY_FLAG_FOREIGN: usize = 1 << 9;
+    const ENTRY_FLAG_EXEC_REQUESTED: usize = 1 << 10;
+    const ENTRY_FLAG_SWAP_TOKEN: usize = 1 << 11;
+
+    const MAX_HUGE_LEVEL: usize = 2; // 1 GiB pages
 
     const PHYS_OFFSET: usize = Self::PAGE_NEGATIVE_MASK + (Self::PAGE_ADDRESS_SIZE >> 1) as usize; // PML4 slot 256 and onwards
+    const TLB_INVALIDATION_BROADCAST: bool = false; // invlpg only affects the local core
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("X8664Arch::init unimplemented");
     }
 
+    #[inline(always)]
+    unsafe fn enable_required_features() {
+        unsafe {
+            let efer = Self::rdmsr(Self::MSR_EFER);
+            Self::wrmsr(Self::MSR_EFER, efer | Self::EFER_NXE);
+
+            let cr4 = Self::cr4() | Self::CR4_PAE | Self::CR4_PGE;
+            asm!("mov cr4, {0}", in(reg) cr4);
+
+            let pat = Self::rdmsr(Self::MSR_PAT);
+            Self::wrmsr(
+                Self::MSR_PAT,
+                (pat & !Self::PAT_ENTRY_4_MASK) | Self::PAT_ENTRY_4_WRITE_COMBINING,
+            );
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn verify_features() -> bool {
+        unsafe {
+            Self::features_valid(
+                Self::rdmsr(Self::MSR_EFER),
+                Self::cr4(),
+                Self::rdmsr(Self::MSR_PAT),
+            )
+        }
+    }
+
     #[inline(always)]
     unsafe fn invalidate(address: VirtualAddress) {
         unsafe {
@@ -37,6 +180,60 @@ impl Arch for X8664Arch {
         }
     }
 
+    #[inline(always)]
+    unsafe fn invalidate_all() {
+        unsafe {
+            // Reloading CR3 keeps global entries, toggling CR4.PGE flushes them as well
+            let cr4 = Self::cr4();
+            if cr4 & Self::CR4_PGE != 0 {
+                asm!("mov cr4, {0}", in(reg) cr4 & !Self::CR4_PGE);
+                asm!("mov cr4, {0}", in(reg) cr4);
+            } else {
+                Self::set_table(TableKind::User, Self::table(TableKind::User));
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn isync() {
+        // cpuid is a serializing instruction
+        Self::cpuid(0, 0);
+    }
+
+    #[inline(always)]
+    fn prefetch_table(phys: PhysicalAddress) {
+        unsafe {
+            let virt = Self::phys_to_virt(phys);
+            asm!("prefetcht0 [{0}]", in(reg) virt.data(), options(nostack, preserves_flags));
+        }
+    }
+
+    #[inline(always)]
+    fn current_exception_level() -> u8 {
+        // Current privilege level is the RPL of the code segment selector
+        let cs: u16;
+        unsafe {
+            asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+        }
+        (cs & 0b11) as u8
+    }
+
+    #[inline(always)]
+    fn tlb_entry_count() -> Option<usize> {
+        // 0 is not probed yet, usize::MAX is unknown
+        static TLB_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+
+        let entries = match TLB_ENTRIES.load(Ordering::Relaxed) {
+            0 => {
+                let entries = Self::probe_tlb_entry_count().unwrap_or(usize::MAX);
+                TLB_ENTRIES.store(entries, Ordering::Relaxed);
+                entries
+            }
+            entries => entries,
+        };
+        (entries != usize::MAX).then_some(entries)
+    }
+
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
         unsafe {
@@ -65,7 +262,7 @@ impl Arch for X8664Arch {
 
 #[cfg(test)]
 mod tests {
-    use super::{VirtualAddress, X8664Arch};
+    use super::{MemoryArea, PhysicalAddress, VirtualAddress, X8664Arch};
     use crate::Arch;
 
     #[test]
@@ -85,6 +282,81 @@ mod tests {
         assert_eq!(X8664Arch::ENTRY_FLAGS_MASK, 0xFFF0_0000_0000_0FFF);
 
         assert_eq!(X8664Arch::PHYS_OFFSET, 0xFFFF_8000_0000_0000);
+        assert!(!X8664Arch::TLB_INVALIDATION_BROADCAST);
+        assert_eq!(X8664Arch::PHYS_MAP_SIZE, 0x0000_8000_0000_0000);
+    }
+    #[test]
+    fn features_valid() {
+        // EFER with LME, LMA and NXE, CR4 with PAE and PGE, PAT with entry 4 write combining
+        let efer = 0xD01;
+        let cr4 = 0x6A0;
+        let pat = 0x0007_0401_0007_0406;
+        assert!(X8664Arch::features_valid(efer, cr4, pat));
+
+        assert!(!X8664Arch::features_valid(efer & !(1 << 11), cr4, pat));
+        assert!(!X8664Arch::features_valid(efer, cr4 & !(1 << 7), pat));
+        // Power-on default PAT has entry 4 write back
+        assert!(!X8664Arch::features_valid(efer, cr4, 0x0007_0406_0007_0406));
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn tlb_entry_count() {
+        // Virtual machines do not always expose the TLB size
+        if let Some(entries) = X8664Arch::tlb_entry_count() {
+            assert!((8..=1 << 16).contains(&entries), "{} TLB entries", entries);
+            assert_eq!(X8664Arch::tlb_entry_count(), Some(entries));
+            assert_eq!(X8664Arch::tlb_flush_all_threshold(), entries / 16);
+        } else {
+            assert_eq!(
+                X8664Arch::tlb_flush_all_threshold(),
+                X8664Arch::TLB_FLUSH_ALL_THRESHOLD
+            );
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn isync() {
+        X8664Arch::isync();
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn ad_bits_hardware_managed() {
+        assert!(X8664Arch::ad_bits_hardware_managed());
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn prefetch_table() {
+        // The physmap is not mapped in a user process, which a prefetch must not fault on
+        X8664Arch::prefetch_table(PhysicalAddress::new(0));
+        X8664Arch::prefetch_table(PhysicalAddress::new(0x1234_5000));
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn current_exception_level() {
+        // Tests run as a user process, unless they are run by a kernel
+        let level = X8664Arch::current_exception_level();
+        assert!(level == 0 || level == 3, "ring {}", level);
+    }
+    #[test]
+    fn physmap_covers() {
+        let area = |base, size| MemoryArea {
+            base: PhysicalAddress::new(base),
+            size,
+        };
+
+        assert!(X8664Arch::physmap_covers(&[
+            area(0x1000, 0x9F000),
+            area(0x10_0000_0000, 0x10_0000_0000),
+        ]));
+        assert!(X8664Arch::physmap_covers(&[area(0x7FFF_FFFF_F000, 0x1000)]));
+        assert!(!X8664Arch::physmap_covers(&[
+            area(0x1000, 0x9F000),
+            area(0x7FFF_FFFF_F000, 0x2000),
+        ]));
+        assert!(!X8664Arch::physmap_covers(&[area(
+            usize::MAX - 0xFFF,
+            0x2000
+        )]));
     }
     #[test]
     fn is_canonical() {
@@ -1,11 +1,15 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 #![allow(warnings)]
 
+#[cfg(feature = "trace")]
+pub use crate::trace::*;
 pub use crate::{allocator::*, arch::*, page::*};
 
 mod allocator;
 mod arch;
 mod page;
+#[cfg(feature = "trace")]
+mod trace;
 
 pub const KILOBYTE: usize = 1024;
 pub const MEGABYTE: usize = KILOBYTE * 1024;
@@ -29,6 +33,8 @@ pub enum TableKind {
 pub struct PhysicalAddress(usize);
 
 impl PhysicalAddress {
+    /// Create a physical address. No masking is performed, the caller is responsible for only
+    /// passing addresses the hardware can represent.
     #[inline(always)]
     pub const fn new(address: usize) -> Self {
         Self(address)
@@ -51,6 +57,8 @@ impl PhysicalAddress {
 pub struct VirtualAddress(usize);
 
 impl VirtualAddress {
+    /// Create a virtual address. No masking or sign extension is performed, the caller is
+    /// responsible for canonicalization.
     #[inline(always)]
     pub const fn new(address: usize) -> Self {
         Self(address)
@@ -75,14 +83,104 @@ impl VirtualAddress {
         }
     }
 }
+/// Identical to [`PhysicalAddress::new`], no masking is performed
+impl From<usize> for PhysicalAddress {
+    #[inline(always)]
+    fn from(address: usize) -> Self {
+        Self::new(address)
+    }
+}
+impl From<PhysicalAddress> for usize {
+    #[inline(always)]
+    fn from(address: PhysicalAddress) -> Self {
+        address.data()
+    }
+}
+
+/// Identical to [`VirtualAddress::new`], no canonicalization is performed
+impl From<usize> for VirtualAddress {
+    #[inline(always)]
+    fn from(address: usize) -> Self {
+        Self::new(address)
+    }
+}
+impl From<VirtualAddress> for usize {
+    #[inline(always)]
+    fn from(address: VirtualAddress) -> Self {
+        address.data()
+    }
+}
+
 impl core::fmt::Debug for VirtualAddress {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "[virt {:#0x}]", self.data())
     }
 }
 
+/// Range of virtual memory
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VirtRange {
+    pub base: VirtualAddress,
+    pub size: usize,
+}
+
+impl VirtRange {
+    #[inline(always)]
+    pub const fn new(base: VirtualAddress, size: usize) -> Self {
+        Self { base, size }
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address.data().wrapping_sub(self.base.data()) < self.size
+    }
+
+    #[inline(always)]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        (other.size > 0 && self.contains(other.base))
+            || (self.size > 0 && other.contains(self.base))
+    }
+}
+
+/// Range of physical memory
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PhysRange {
+    pub base: PhysicalAddress,
+    pub size: usize,
+}
+
+impl PhysRange {
+    #[inline(always)]
+    pub const fn new(base: PhysicalAddress, size: usize) -> Self {
+        Self { base, size }
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, address: PhysicalAddress) -> bool {
+        address.data().wrapping_sub(self.base.data()) < self.size
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct MemoryArea {
     pub base: PhysicalAddress,
     pub size: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PhysicalAddress, VirtualAddress};
+
+    #[test]
+    fn address_conversions() {
+        for address in [0, 0x1000, 0x7FFF_FFFF, usize::MAX] {
+            let phys = PhysicalAddress::from(address);
+            assert_eq!(phys, PhysicalAddress::new(address));
+            assert_eq!(usize::from(phys), address);
+
+            let virt: VirtualAddress = address.into();
+            assert_eq!(virt, VirtualAddress::new(address));
+            assert_eq!(usize::from(virt), address);
+        }
+    }
+}
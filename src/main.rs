@@ -27,15 +27,15 @@ unsafe fn dump_tables<A: Arch>(table: PageTable<A>) {
         let level = table.level();
         for i in 0..A::PAGE_ENTRIES {
             if level == 0 {
-                if let Some(entry) = table.entry(i) {
-                    if entry.present() {
-                        let base = table.entry_base(i).unwrap();
-                        println!(
-                            "0x{:X}: 0x{:X}",
-                            base.data(),
-                            entry.address().unwrap().data()
-                        );
-                    }
+                if let Some(entry) = table.entry(i)
+                    && entry.present()
+                {
+                    let base = table.entry_base(i).unwrap();
+                    println!(
+                        "0x{:X}: 0x{:X}",
+                        base.data(),
+                        entry.address().unwrap().data()
+                    );
                 }
             } else {
                 if let Some(next) = table.next(i) {
@@ -65,6 +65,8 @@ impl<A: Arch> SlabNode<A> {
         Self::new(PhysicalAddress::new(0), 0)
     }
 
+    /// # Safety
+    /// `phys` must be an unused frame reachable through the physmap.
     pub unsafe fn insert(&mut self, phys: PhysicalAddress) {
         unsafe {
             let virt = A::phys_to_virt(phys);
@@ -74,6 +76,8 @@ impl<A: Arch> SlabNode<A> {
         }
     }
 
+    /// # Safety
+    /// All frames in the list must still be reachable through the physmap.
     pub unsafe fn remove(&mut self) -> Option<PhysicalAddress> {
         unsafe {
             if self.count > 0 {
@@ -96,6 +100,9 @@ pub struct SlabAllocator<A> {
 }
 
 impl<A: Arch> SlabAllocator<A> {
+    /// # Safety
+    /// The first `offset` bytes of `areas` must be the only memory in use, and all areas must be
+    /// reachable through the physmap.
     pub unsafe fn new(areas: &'static [MemoryArea], offset: usize) -> Self {
         unsafe {
             let mut allocator = Self {
@@ -125,16 +132,18 @@ impl<A: Arch> SlabAllocator<A> {
         }
     }
 
+    /// # Safety
+    /// The free lists must only contain frames reachable through the physmap.
     pub unsafe fn allocate(&mut self, size: usize) -> Option<PhysicalAddress> {
         unsafe {
             for level in 0..A::PAGE_LEVELS - 1 {
                 let level_shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
                 let level_size = 1 << level_shift;
-                if size <= level_size {
-                    if let Some(base) = self.nodes[level].remove() {
-                        self.free(base.add(size), level_size - size);
-                        return Some(base);
-                    }
+                if size <= level_size
+                    && let Some(base) = self.nodes[level].remove()
+                {
+                    self.free(base.add(size), level_size - size);
+                    return Some(base);
                 }
             }
             None
@@ -143,6 +152,8 @@ impl<A: Arch> SlabAllocator<A> {
 
     //TODO: This causes fragmentation, since neighbors are not identified
     //TODO: remainders less than PAGE_SIZE will be lost
+    /// # Safety
+    /// `base` to `base + size` must be unused memory reachable through the physmap.
     pub unsafe fn free(&mut self, mut base: PhysicalAddress, mut size: usize) {
         unsafe {
             for level in (0..A::PAGE_LEVELS - 1).rev() {
@@ -158,6 +169,8 @@ impl<A: Arch> SlabAllocator<A> {
         }
     }
 
+    /// # Safety
+    /// The free lists must be consistent with their counts.
     pub unsafe fn remaining(&mut self) -> usize {
         let mut remaining = 0;
         for level in (0..A::PAGE_LEVELS - 1).rev() {
@@ -178,6 +191,10 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
         }
 
         println!("Memory: {}", format_size(size));
+        assert!(
+            A::physmap_covers(areas),
+            "physmap window does not cover all memory"
+        );
 
         // Create a basic allocator for the first pages
         let mut bump_allocator = BumpAllocator::<A>::new(areas, 0);
@@ -211,22 +228,22 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
             {
                 let phys_opt = allocator.allocate_one();
                 println!("page {}: {:X?}", i, phys_opt);
-                if i % 3 == 0 {
-                    if let Some(phys) = phys_opt {
-                        println!("free {}: {:X?}", i, phys_opt);
-                        allocator.free_one(phys);
-                    }
+                if i % 3 == 0
+                    && let Some(phys) = phys_opt
+                {
+                    println!("free {}: {:X?}", i, phys_opt);
+                    allocator.free_one(phys);
                 }
             }
 
             {
                 let phys_opt = allocator.allocate(FrameCount::new(16));
                 println!("page*16 {}: {:X?}", i, phys_opt);
-                if i % 2 == 0 {
-                    if let Some(phys) = phys_opt {
-                        println!("free*16 {}: {:X?}", i, phys_opt);
-                        allocator.free(phys, FrameCount::new(16));
-                    }
+                if i % 2 == 0
+                    && let Some(phys) = phys_opt
+                {
+                    println!("free*16 {}: {:X?}", i, phys_opt);
+                    allocator.free(phys, FrameCount::new(16));
                 }
             }
         }
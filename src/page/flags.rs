@@ -2,6 +2,39 @@ use core::{fmt, marker::PhantomData};
 
 use crate::Arch;
 
+/// Shareability domain of a mapping, which decides which cores cache maintenance and barriers
+/// apply to. Only aarch64 encodes this in entries, it is ignored elsewhere.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Shareability {
+    /// Coherent only for the local core. Device memory should use this.
+    NonShareable,
+    OuterShareable,
+    /// Coherent for all cores in the inner domain, the default for normal memory
+    InnerShareable,
+}
+
+/// Access allowed to a kernel page, as a shorthand for the matching [`PageFlags`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Permissions {
+    ReadWrite,
+    ReadOnly,
+    ReadExecute,
+    /// Not mapped at all, so every access faults
+    None,
+}
+
+impl Permissions {
+    /// Flags for a kernel page with these permissions, or `None` if it should not be mapped
+    pub fn flags<A: Arch>(self) -> Option<PageFlags<A>> {
+        match self {
+            Self::ReadWrite => Some(PageFlags::new().write(true)),
+            Self::ReadOnly => Some(PageFlags::new()),
+            Self::ReadExecute => Some(PageFlags::new().execute(true)),
+            Self::None => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct PageFlags<A> {
     data: usize,
@@ -45,6 +78,17 @@ impl<A: Arch> PageFlags<A> {
         self.data
     }
 
+    /// Create flags from the raw bits of an entry, masking off any address bits
+    #[inline(always)]
+    pub fn from_raw(bits: usize) -> Self {
+        unsafe { Self::from_data(bits & A::ENTRY_FLAGS_MASK) }
+    }
+
+    #[inline(always)]
+    pub fn raw(&self) -> usize {
+        self.data & A::ENTRY_FLAGS_MASK
+    }
+
     #[must_use]
     #[inline(always)]
     pub fn custom_flag(mut self, flag: usize, value: bool) -> Self {
@@ -109,6 +153,7 @@ impl<A: Arch> PageFlags<A> {
         // Architecture may use no exec or exec, support either
         self.custom_flag(A::ENTRY_FLAG_NO_EXEC, !value)
             .custom_flag(A::ENTRY_FLAG_EXEC, value)
+            .custom_flag(A::ENTRY_FLAG_EXEC_REQUESTED, value)
     }
 
     #[inline(always)]
@@ -117,6 +162,90 @@ impl<A: Arch> PageFlags<A> {
         self.data & (A::ENTRY_FLAG_NO_EXEC | A::ENTRY_FLAG_EXEC) == A::ENTRY_FLAG_EXEC
     }
 
+    #[must_use]
+    #[inline(always)]
+    pub fn accessed(self, value: bool) -> Self {
+        self.custom_flag(A::ENTRY_FLAG_ACCESSED, value)
+    }
+
+    #[inline(always)]
+    pub fn has_accessed(&self) -> bool {
+        self.has_flag(A::ENTRY_FLAG_ACCESSED)
+    }
+
+    #[must_use]
+    #[inline(always)]
+    pub fn dirty(self, value: bool) -> Self {
+        self.custom_flag(A::ENTRY_FLAG_DIRTY, value)
+    }
+
+    #[inline(always)]
+    pub fn has_dirty(&self) -> bool {
+        // Architectures without a dirty flag never report a page as dirty
+        A::ENTRY_FLAG_DIRTY != 0 && self.has_flag(A::ENTRY_FLAG_DIRTY)
+    }
+
+    /// Flags for a clean, read-only and non-executable page that is already marked accessed,
+    /// such as a page of a file-backed mapping.
+    ///
+    /// As the page can not be written through this mapping, it stays clean, and reclaim may drop
+    /// it without writing it back first. Making it writable again requires a remap, after which
+    /// the dirty flag has to be checked again before dropping it.
+    #[must_use]
+    #[inline(always)]
+    pub fn clean_readonly(self) -> Self {
+        self.write(false).dirty(false).execute(false).accessed(true)
+    }
+
+    /// Mark the page as accessed and dirty already, for a page that is written right after it is
+    /// mapped, such as a stack page. The first write then does not fault to set the dirty flag on
+    /// architectures that track it in software.
+    ///
+    /// This is only a performance hint, so it has no effect on architectures without a dirty
+    /// flag. The page is reported as dirty even if it is never written.
+    #[must_use]
+    #[inline(always)]
+    pub fn predirty(self) -> Self {
+        self.accessed(true).dirty(true)
+    }
+
+    /// Mark the frame or table of an entry as not allocated by the mapper, so that it is never
+    /// freed, see [`crate::PageMapper::adopt`]
+    #[must_use]
+    #[inline(always)]
+    pub fn foreign(self, value: bool) -> Self {
+        self.custom_flag(A::ENTRY_FLAG_FOREIGN, value)
+    }
+
+    #[inline(always)]
+    pub fn has_foreign(&self) -> bool {
+        self.has_flag(A::ENTRY_FLAG_FOREIGN)
+    }
+
+    #[must_use]
+    #[inline(always)]
+    pub fn shareability(self, value: Shareability) -> Self {
+        let flag = match value {
+            Shareability::NonShareable => 0,
+            Shareability::OuterShareable => A::ENTRY_FLAG_OUTER_SHAREABLE,
+            Shareability::InnerShareable => A::ENTRY_FLAG_INNER_SHAREABLE,
+        };
+        self.custom_flag(
+            A::ENTRY_FLAG_OUTER_SHAREABLE | A::ENTRY_FLAG_INNER_SHAREABLE,
+            false,
+        )
+        .custom_flag(flag, true)
+    }
+
+    /// Mark the entry as a huge page, only valid for entries in tables above level 0
+    #[must_use]
+    #[inline(always)]
+    pub fn huge(self, value: bool) -> Self {
+        // Architecture may use huge or not huge, support either
+        self.custom_flag(A::ENTRY_FLAG_NO_HUGE, !value)
+            .custom_flag(A::ENTRY_FLAG_HUGE, value)
+    }
+
     #[must_use]
     #[inline(always)]
     pub fn global(self, value: bool) -> Self {
@@ -143,3 +272,26 @@ impl<A: Arch> fmt::Debug for PageFlags<A> {
             .finish()
     }
 }
+
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::PageFlags;
+    use crate::{Arch, X8664Arch};
+
+    #[test]
+    fn from_raw() {
+        let flags = PageFlags::<X8664Arch>::from_raw(0x8000_1234_5678_9067);
+        assert_eq!(flags.raw(), 0x8000_0000_0000_0067);
+        assert!(flags.has_present());
+        assert!(flags.has_write());
+        assert!(flags.has_user());
+        assert!(!flags.has_execute());
+
+        for bits in [0, usize::MAX, 0x0000_00FF_FFFF_F000, 0x8000_0000_0000_0003] {
+            assert_eq!(
+                PageFlags::<X8664Arch>::from_raw(bits).raw(),
+                bits & X8664Arch::ENTRY_FLAGS_MASK
+            );
+        }
+    }
+}
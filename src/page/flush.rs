@@ -72,3 +72,214 @@ impl<A: Arch, T: Flusher<A> + ?Sized> Flusher<A> for &mut T {
 impl<A: Arch> Flusher<A> for () {
     fn consume(&mut self, _: PageFlush<A>) {}
 }
+
+/// When the TLB is flushed for a change made by a single operation
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FlushPolicy {
+    /// Flush before returning
+    #[default]
+    Immediate,
+    /// Return the flush to the caller, such as to batch it with others
+    Deferred,
+    /// Never flush. This is only sound for tables that are not live on any core, such as cold
+    /// tables of an address space that is being built.
+    None,
+}
+
+impl FlushPolicy {
+    /// Handle `flush` according to the policy, returning it if it is deferred
+    pub unsafe fn apply<A: Arch>(self, flush: PageFlush<A>) -> Option<PageFlush<A>> {
+        match self {
+            Self::Immediate => {
+                flush.flush();
+                None
+            }
+            Self::Deferred => Some(flush),
+            Self::None => {
+                unsafe { flush.ignore() };
+                None
+            }
+        }
+    }
+}
+
+/// Maximum number of separate page ranges a [`FlushSet`] can track
+pub const FLUSH_SET_RANGES: usize = 16;
+
+/// Invalidations that a [`FlushSet`] will issue
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlushPlan<'a> {
+    None,
+    /// Page count of each range, sorted by address
+    Ranges(&'a [(VirtualAddress, usize)]),
+    All,
+}
+
+/// Flusher that collects flushes, merging duplicate and adjacent pages into ranges, so that a
+/// batch of operations can be flushed with as few invalidations as possible.
+///
+/// Once the pages exceed the flush-all threshold of the architecture, or the ranges do not fit
+/// in [`FLUSH_SET_RANGES`], the whole TLB is flushed instead.
+#[must_use = "The page table must be flushed, or the changes unsafely ignored"]
+pub struct FlushSet<A> {
+    // Page aligned base and page count, sorted by base, never adjacent
+    ranges: [(VirtualAddress, usize); FLUSH_SET_RANGES],
+    len: usize,
+    overflowed: bool,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> FlushSet<A> {
+    pub fn new() -> Self {
+        Self {
+            ranges: [(VirtualAddress::new(0), 0); FLUSH_SET_RANGES],
+            len: 0,
+            overflowed: false,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Add the page containing `virt`
+    pub fn add(&mut self, virt: VirtualAddress) {
+        let page = VirtualAddress::new(virt.data() & !A::PAGE_OFFSET_MASK);
+        let end = |(base, count): (VirtualAddress, usize)| base.add(count * A::PAGE_SIZE);
+
+        // Index of the first range after the page
+        let i = self.ranges[..self.len].partition_point(|&(base, _)| base <= page);
+        if i > 0 && end(self.ranges[i - 1]) > page {
+            return;
+        }
+        let merge_prev = i > 0 && end(self.ranges[i - 1]) == page;
+        let merge_next = i < self.len && page.add(A::PAGE_SIZE) == self.ranges[i].0;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.ranges[i - 1].1 += 1 + self.ranges[i].1;
+                self.ranges.copy_within(i + 1..self.len, i);
+                self.len -= 1;
+            }
+            (true, false) => self.ranges[i - 1].1 += 1,
+            (false, true) => self.ranges[i] = (page, self.ranges[i].1 + 1),
+            (false, false) if self.len < FLUSH_SET_RANGES => {
+                self.ranges.copy_within(i..self.len, i + 1);
+                self.ranges[i] = (page, 1);
+                self.len += 1;
+            }
+            (false, false) => self.overflowed = true,
+        }
+    }
+
+    /// Number of distinct pages added, not counting those added after the ranges overflowed
+    pub fn pages(&self) -> usize {
+        self.ranges[..self.len]
+            .iter()
+            .map(|&(_, count)| count)
+            .sum()
+    }
+
+    pub fn plan(&self) -> FlushPlan<'_> {
+        if self.overflowed || self.pages() > A::tlb_flush_all_threshold() {
+            FlushPlan::All
+        } else if self.len == 0 {
+            FlushPlan::None
+        } else {
+            FlushPlan::Ranges(&self.ranges[..self.len])
+        }
+    }
+
+    pub fn apply(self) {
+        unsafe {
+            match self.plan() {
+                FlushPlan::None => {}
+                FlushPlan::Ranges(ranges) => {
+                    for &(base, count) in ranges {
+                        A::invalidate_range(base, count);
+                    }
+                }
+                FlushPlan::All => A::invalidate_all(),
+            }
+        }
+    }
+}
+impl<A: Arch> Flusher<A> for FlushSet<A> {
+    fn consume(&mut self, flush: PageFlush<A>) {
+        unsafe {
+            self.add(flush.virt);
+            flush.ignore();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use super::{FlushPlan, FlushSet, Flusher, PageFlush, PageFlushAll, FLUSH_SET_RANGES};
+    use crate::{Arch, EmulateArch, PageFlags, PageMapper, TableKind, VirtualAddress, MEGABYTE};
+
+    type A = EmulateArch;
+
+    fn page(i: usize) -> VirtualAddress {
+        VirtualAddress::new(i * A::PAGE_SIZE)
+    }
+
+    #[test]
+    fn coalesce() {
+        let _allocator = unsafe { A::init_test() };
+        let mut set = FlushSet::<A>::new();
+        assert_eq!(set.plan(), FlushPlan::None);
+
+        for virt in [page(1), page(1), page(2), page(4).add(0xFFF), page(10)] {
+            set.consume(PageFlush::new(virt));
+        }
+        assert_eq!(
+            set.plan(),
+            FlushPlan::Ranges(&[(page(1), 2), (page(4), 1), (page(10), 1)])
+        );
+
+        // Bridges the gap between two ranges
+        set.add(page(3));
+        set.add(page(9));
+        assert_eq!(set.plan(), FlushPlan::Ranges(&[(page(1), 4), (page(9), 2)]));
+        assert_eq!(set.pages(), 6);
+        set.apply();
+
+        // Too many pages to flush one by one
+        let mut set = FlushSet::<A>::new();
+        for i in 0..=A::tlb_flush_all_threshold() {
+            set.add(page(i));
+        }
+        assert_eq!(set.plan(), FlushPlan::All);
+
+        // Too many separate ranges to track
+        let mut set = FlushSet::<A>::new();
+        for i in 0..FLUSH_SET_RANGES {
+            set.add(page(2 * i));
+        }
+        assert!(
+            matches!(set.plan(), FlushPlan::Ranges(ranges) if ranges.len() == FLUSH_SET_RANGES)
+        );
+        set.add(page(2 * FLUSH_SET_RANGES));
+        assert_eq!(set.plan(), FlushPlan::All);
+        set.apply();
+    }
+
+    #[test]
+    fn flush_all_global() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            mapper
+                .map(virt, PageFlags::new().write(true).global(true))
+                .unwrap()
+                .flush();
+            mapper.unmap(virt, false).unwrap().ignore();
+
+            // Switching tables keeps global translations
+            A::set_table(TableKind::Kernel, A::table(TableKind::Kernel));
+            assert!(A::hw_translate(virt).is_some());
+
+            PageFlushAll::<A>::new().flush();
+            assert!(A::hw_translate(virt).is_none());
+        }
+    }
+}
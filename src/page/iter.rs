@@ -0,0 +1,181 @@
+use core::{iter::Peekable, marker::PhantomData};
+
+use super::{PageEntry, PageTable};
+use crate::{Arch, PageFlags, PhysicalAddress, VirtRange, VirtualAddress};
+
+// Deepest table tree that can be iterated
+pub(crate) const MAX_PAGE_LEVELS: usize = 5;
+
+/// Iterator over the present leaf entries of a table tree, in address order. Yields the
+/// sign-extended virtual address, physical address, flags and size of each page, which is larger
+/// than `A::PAGE_SIZE` for huge pages.
+pub struct PageIter<A> {
+    // Physical address, base and next index of each table from the top level down
+    stack: [(PhysicalAddress, VirtualAddress, usize); MAX_PAGE_LEVELS],
+    depth: usize,
+    // Top-level index to stop at
+    end: usize,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> PageIter<A> {
+    /// Iterate over the entries of `table`, which must be a top-level table, that are below
+    /// top-level index `end`
+    pub unsafe fn new(table: &PageTable<A>, end: usize) -> Self {
+        assert!(A::PAGE_LEVELS <= MAX_PAGE_LEVELS);
+        let mut stack = [(PhysicalAddress::new(0), VirtualAddress::new(0), 0); MAX_PAGE_LEVELS];
+        stack[0] = (table.phys(), table.base(), 0);
+        Self {
+            stack,
+            depth: 1,
+            end: end.min(A::PAGE_ENTRIES),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<A: Arch> Iterator for PageIter<A> {
+    type Item = (VirtualAddress, PhysicalAddress, PageFlags<A>, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            while self.depth > 0 {
+                let (phys, base, i) = self.stack[self.depth - 1];
+                let end = if self.depth == 1 {
+                    self.end
+                } else {
+                    A::PAGE_ENTRIES
+                };
+                if i >= end {
+                    self.depth -= 1;
+                    continue;
+                }
+                self.stack[self.depth - 1].2 += 1;
+
+                let table = PageTable::<A>::new(base, phys, A::PAGE_LEVELS - self.depth);
+                let Some(entry) = table.entry(i) else {
+                    continue;
+                };
+                let Ok(address) = entry.address() else {
+                    continue;
+                };
+                if let Some(next) = table.next(i) {
+                    A::prefetch_table(next.phys());
+                    self.stack[self.depth] = (next.phys(), next.base(), 0);
+                    self.depth += 1;
+                } else if let Some(virt) = table.entry_base(i) {
+                    return Some((
+                        sign_extend::<A>(virt),
+                        address,
+                        entry.flags(),
+                        table.entry_size(),
+                    ));
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Iterator over the mapped ranges of a table tree, in address order. Consecutive pages with the
+/// same flags whose physical addresses are contiguous as well, such as the physmap, are merged
+/// into one range.
+pub struct RangeIter<A: Arch> {
+    pages: Peekable<PageIter<A>>,
+}
+
+impl<A: Arch> RangeIter<A> {
+    pub fn new(pages: PageIter<A>) -> Self {
+        Self {
+            pages: pages.peekable(),
+        }
+    }
+}
+
+impl<A: Arch> Iterator for RangeIter<A> {
+    type Item = (VirtRange, PhysicalAddress, PageFlags<A>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (virt, phys, flags, mut size) = self.pages.next()?;
+        while let Some(&(next_virt, next_phys, next_flags, next_size)) = self.pages.peek() {
+            if next_virt != virt.add(size)
+                || next_phys != phys.add(size)
+                || next_flags.data() != flags.data()
+            {
+                break;
+            }
+            size += next_size;
+            self.pages.next();
+        }
+        Some((VirtRange::new(virt, size), phys, flags))
+    }
+}
+
+/// Entry of one table visited by a [`Walk`]
+#[derive(Clone, Copy)]
+pub struct WalkStep<A> {
+    /// Physical address of the table
+    pub table: PhysicalAddress,
+    pub level: usize,
+    pub index: usize,
+    pub entry: PageEntry<A>,
+    // Lowest virtual address mapped through the table
+    base: VirtualAddress,
+}
+
+impl<A: Arch> WalkStep<A> {
+    /// Virtual range that the table of this step maps, such as 1 GiB for a table of level 1 with
+    /// 4 KiB pages. For the top-level table, this is the whole address space starting at 0, as
+    /// the upper half is only reached by sign extension. The size saturates if the whole space
+    /// does not fit, such as on 32-bit x86.
+    pub fn covers(&self) -> VirtRange {
+        let shift = (self.level + 1) * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
+        let size = 1usize.checked_shl(shift as u32).unwrap_or(usize::MAX);
+        VirtRange::new(sign_extend::<A>(self.base), size)
+    }
+}
+
+/// Iterator over the entries that translate an address, from the top-level table down. Ends
+/// with the entry that maps the page, or with the first entry that is not present.
+pub struct Walk<A> {
+    table: Option<PageTable<A>>,
+    virt: VirtualAddress,
+}
+
+impl<A: Arch> Walk<A> {
+    /// Walk the tables below `table` for `virt`
+    pub unsafe fn new(table: PageTable<A>, virt: VirtualAddress) -> Self {
+        Self {
+            table: Some(table),
+            virt,
+        }
+    }
+}
+
+impl<A: Arch> Iterator for Walk<A> {
+    type Item = WalkStep<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let table = self.table.take()?;
+            let index = table.index_of(self.virt)?;
+            let entry = table.entry(index)?;
+            self.table = table.next(index);
+            Some(WalkStep {
+                table: table.phys(),
+                level: table.level(),
+                index,
+                entry,
+                base: table.base(),
+            })
+        }
+    }
+}
+
+fn sign_extend<A: Arch>(virt: VirtualAddress) -> VirtualAddress {
+    if virt.data() & (1 << (A::PAGE_ADDRESS_SHIFT - 1)) != 0 {
+        VirtualAddress::new(virt.data() | A::PAGE_NEGATIVE_MASK)
+    } else {
+        virt
+    }
+}
@@ -1,27 +1,400 @@
-use core::marker::PhantomData;
+use core::{
+    cell::Cell,
+    marker::PhantomData,
+    ops::{ControlFlow, Range},
+};
 
 use crate::{
-    Arch, FrameAllocator, PageEntry, PageFlags, PageFlush, PageTable, PhysicalAddress, TableKind,
-    VirtualAddress,
+    Arch, FlushPolicy, Flusher, FrameAllocator, FrameCount, MemoryArea, PageEntry, PageFlags,
+    PageFlush, PageFlushAll, PageIter, PageTable, Permissions, PhysicalAddress, RangeIter,
+    TableKind, VirtRange, VirtualAddress, Walk, MAX_PAGE_LEVELS,
 };
+#[cfg(feature = "trace")]
+use crate::{RmmTrace, TraceEvent};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MapError {
+    /// The allocator ran out of frames for pages or tables
+    OutOfFrames,
+    /// The virtual address is not covered by the table
+    InvalidAddress,
+    /// The output buffer is too small for the scatter-gather list
+    TooManySegments,
+    /// The virtual address is inside a range protected from mapping
+    Protected,
+    /// The virtual address is already covered by a huge page
+    InHugePage,
+    /// The fault was already handled, such as by another core
+    AlreadyHandled,
+    /// All slots of the scratch pool are in use
+    NoScratchSlot,
+    /// The virtual address is already mapped, to the contained table, or to the contained frame
+    /// with [`PageMapper::with_double_map_guard`]
+    AlreadyMapped(PhysicalAddress),
+    /// The swap token has more than [`SWAP_TOKEN_BITS`] bits
+    SwapTokenTooLarge,
+    /// The flags cannot be used for a huge page, such as write combining on x86_64
+    InvalidFlags,
+}
+
+/// Number of bits of a swap token, see [`PageMapper::swap_out`]
+pub const SWAP_TOKEN_BITS: u32 = usize::BITS - 2;
+
+// Swap entries have the present bit, bit 0 on all architectures, clear and
+// `A::ENTRY_FLAG_SWAP_TOKEN` set, with the token spread over the bits around them
+fn swap_token_masks<A: Arch>() -> (usize, usize) {
+    let below = (A::ENTRY_FLAG_SWAP_TOKEN - 1) & !1;
+    let above = !(A::ENTRY_FLAG_SWAP_TOKEN | (A::ENTRY_FLAG_SWAP_TOKEN - 1));
+    (below, above)
+}
+
+fn swap_entry<A: Arch>(token: u64) -> PageEntry<A> {
+    let (below, above) = swap_token_masks::<A>();
+    let token = token as usize;
+    PageEntry::from_data((token << 1) & below | (token << 2) & above | A::ENTRY_FLAG_SWAP_TOKEN)
+}
+
+fn entry_swap_token<A: Arch>(entry: PageEntry<A>) -> Option<u64> {
+    let (below, above) = swap_token_masks::<A>();
+    let data = entry.data();
+    (!entry.present() && data & A::ENTRY_FLAG_SWAP_TOKEN != 0)
+        .then(|| ((data & below) >> 1 | (data & above) >> 2) as u64)
+}
+
+/// Reason why [`PageMapper::check_half_isolation`] failed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IsolationError {
+    /// The table at this physical address is reachable from both the user and the kernel half,
+    /// which exposes the mappings below it to both
+    Aliased(PhysicalAddress),
+    /// The buffer is too small for the tables of the user half
+    BufferTooSmall,
+}
+
+/// Options for mapping a range of pages
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapOptions {
+    /// Map the parts of the range that are suitably aligned with huge pages. This is best-effort,
+    /// the translations are the same as with base pages.
+    pub allow_huge: bool,
+}
+
+/// How the references and writes to pages are tracked, such as for reclaim
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessTracking {
+    /// The MMU sets the accessed and dirty flags, which are scanned and cleared periodically
+    Scan,
+    /// The first access and write to a page fault, and the fault handler sets the flags with
+    /// [`PageMapper::handle_af_fault`] and [`PageMapper::fault_set_dirty_writable`]
+    Fault,
+}
+
+impl AccessTracking {
+    /// Tracking required by the current configuration of the MMU
+    pub fn current<A: Arch>() -> Self {
+        if A::ad_bits_hardware_managed() {
+            Self::Scan
+        } else {
+            Self::Fault
+        }
+    }
+}
+
+/// Bytes mapped by an address space for each page size
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SizeBreakdown {
+    /// Bytes mapped by pages of each level, index 0 is base pages
+    pub by_level: [usize; MAX_PAGE_LEVELS],
+}
+
+impl SizeBreakdown {
+    pub fn total(&self) -> usize {
+        self.by_level.iter().sum()
+    }
+}
+
+// Most entries a top-level table can have, 1024 on x86
+const MAX_TOP_ENTRIES: usize = 1024;
+
+/// Set of top-level table indices, such as the populated slots of an address space
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TopLevelSet {
+    bits: [u64; MAX_TOP_ENTRIES / 64],
+}
+
+impl TopLevelSet {
+    pub fn empty() -> Self {
+        Self {
+            bits: [0; MAX_TOP_ENTRIES / 64],
+        }
+    }
+
+    pub fn insert(&mut self, i: usize) {
+        self.bits[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        i < MAX_TOP_ENTRIES && self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the indices in the set, in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_TOP_ENTRIES).filter(|&i| self.contains(i))
+    }
+}
+
+/// Most slots a [`ScratchPool`] can have
+pub const SCRATCH_POOL_MAX_SLOTS: usize = 64;
+
+/// Virtual pages reserved by [`PageMapper::install_scratch_region`] for temporary mappings with
+/// [`PageMapper::with_temp_map`].
+///
+/// A pool belongs to a single core, as slots are not claimed atomically and the temporary
+/// mappings are only flushed from the local TLB. Each core needs a pool over its own region.
+pub struct ScratchPool<A> {
+    base: VirtualAddress,
+    slots: usize,
+    // Bit for each slot that is in use
+    used: Cell<u64>,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> ScratchPool<A> {
+    pub fn base(&self) -> VirtualAddress {
+        self.base
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    fn claim(&self) -> Option<usize> {
+        let used = self.used.get();
+        let slot = (!used).trailing_zeros() as usize;
+        if slot >= self.slots {
+            return None;
+        }
+        self.used.set(used | 1 << slot);
+        Some(slot)
+    }
+
+    fn release(&self, slot: usize) {
+        self.used.set(self.used.get() & !(1 << slot));
+    }
+}
+
+/// Level of the largest page that can map the start of `size` bytes at `virt` to `phys`, which
+/// both have to be aligned to it. Level 0 is a base page.
+pub fn chunk_for_mapping<A: Arch>(
+    virt: VirtualAddress,
+    phys: PhysicalAddress,
+    size: usize,
+) -> usize {
+    (1..=A::MAX_HUGE_LEVEL.min(A::PAGE_LEVELS - 1))
+        .rev()
+        .find(|level| {
+            let page_size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            size >= page_size && (virt.data() | phys.data()) & (page_size - 1) == 0
+        })
+        .unwrap_or(0)
+}
+
+/// Number of tables below the top level needed to map `mapped_bytes` of contiguous memory, as
+/// `(best, worst)`, such as to size a pool of tables reserved up front. The best case is a range
+/// aligned to the largest page it can use, the worst case a range starting on the last page of a
+/// table of every level.
+///
+/// With `use_huge`, pages of up to [`Arch::MAX_HUGE_LEVEL`] are used as by
+/// [`chunk_for_mapping`], assuming that the physical and virtual addresses are equally aligned.
+pub fn table_overhead<A: Arch>(mapped_bytes: usize, use_huge: bool) -> (usize, usize) {
+    let size = mapped_bytes.next_multiple_of(A::PAGE_SIZE);
+    if size == 0 {
+        return (0, 0);
+    }
+    let huge_level = if use_huge {
+        A::MAX_HUGE_LEVEL.min(A::PAGE_LEVELS - 1)
+    } else {
+        0
+    };
+
+    let mut best = 0;
+    let mut worst = 0;
+    for level in 0..A::PAGE_LEVELS - 1 {
+        // Bytes mapped through one table of this level
+        let span = A::PAGE_SIZE << ((level + 1) * A::PAGE_ENTRY_SHIFT);
+        let touched = (size - A::PAGE_SIZE).div_ceil(span) + 1;
+        if level < huge_level {
+            // Larger pages map everything but the unaligned ends
+            best += usize::from(size % span != 0);
+            worst += touched.min(2);
+        } else {
+            best += size.div_ceil(span);
+            worst += touched;
+        }
+    }
+    (best, worst)
+}
+
+/// Plan the mapping of `areas` into the physmap, using the largest pages their alignment
+/// permits. Each page is written to `out` as `(phys, virt, page_size)`, in order.
+///
+/// Returns the number of pages in the plan. If that is more than fits in `out`, only the start
+/// of the plan is written.
+pub fn plan_physmap<A: Arch>(
+    areas: &[MemoryArea],
+    out: &mut [(PhysicalAddress, VirtualAddress, usize)],
+) -> usize {
+    let mut count = 0;
+    for page in physmap_pages::<A>(areas) {
+        if let Some(slot) = out.get_mut(count) {
+            *slot = page;
+        }
+        count += 1;
+    }
+    count
+}
+
+fn physmap_pages<A: Arch>(
+    areas: &[MemoryArea],
+) -> impl Iterator<Item = (PhysicalAddress, VirtualAddress, usize)> + '_ {
+    areas.iter().flat_map(|area| {
+        // Only whole pages inside of the area are mapped
+        let start = (area.base.data() + A::PAGE_OFFSET_MASK) & !A::PAGE_OFFSET_MASK;
+        let end = (area.base.data() + area.size) & !A::PAGE_OFFSET_MASK;
+        let mut phys = PhysicalAddress::new(start);
+        core::iter::from_fn(move || {
+            if phys.data() >= end {
+                return None;
+            }
+            let virt = unsafe { A::phys_to_virt(phys) };
+            let level = chunk_for_mapping::<A>(virt, phys, end - phys.data());
+            let page_size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            let page = (phys, virt, page_size);
+            phys = phys.add(page_size);
+            Some(page)
+        })
+    })
+}
 
 pub struct PageMapper<A, F> {
     table_kind: TableKind,
     table_addr: PhysicalAddress,
     allocator: F,
+    protected: &'static [VirtRange],
+    default_nx: bool,
+    table_walk_uncached: bool,
+    double_map_guard: bool,
+    #[cfg(feature = "trace")]
+    trace: Option<&'static dyn RmmTrace>,
     _phantom: PhantomData<fn() -> A>,
 }
 
 impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
+    /// Create a mapper over the top-level table at `table_addr`. Tables are always reached
+    /// through the physmap, so this does not need to be the active table.
     pub unsafe fn new(table_kind: TableKind, table_addr: PhysicalAddress, allocator: F) -> Self {
         Self {
             table_kind,
             table_addr,
             allocator,
+            protected: &[],
+            default_nx: false,
+            table_walk_uncached: false,
+            double_map_guard: false,
+            #[cfg(feature = "trace")]
+            trace: None,
             _phantom: PhantomData,
         }
     }
 
+    /// Refuse to map anything that overlaps `protected`, such as the physmap or the kernel image.
+    /// Mapping into a protected range fails, with [`MapError::Protected`] from the `try_` variants.
+    pub fn with_protected(mut self, protected: &'static [VirtRange]) -> Self {
+        self.protected = protected;
+        self
+    }
+
+    /// Make every page mapped by this mapper non-executable, unless its flags request execution
+    /// with [`PageFlags::execute`]. Existing mappings and remaps are not affected.
+    pub fn with_default_nx(mut self, default_nx: bool) -> Self {
+        self.default_nx = default_nx;
+        self
+    }
+
+    pub fn default_nx(&self) -> bool {
+        self.default_nx
+    }
+
+    /// Make the MMU read the tables of this mapper without going through the caches once it is
+    /// made current, where the architecture can control that, such as with TCR_EL1 on aarch64.
+    /// Meant for chasing cache coherency issues with table updates, as every TLB miss gets
+    /// considerably slower.
+    pub fn with_table_walk_uncached(mut self, table_walk_uncached: bool) -> Self {
+        self.table_walk_uncached = table_walk_uncached;
+        self
+    }
+
+    pub fn table_walk_uncached(&self) -> bool {
+        self.table_walk_uncached
+    }
+
+    /// Refuse to map over a page that is already mapped, failing with [`MapError::AlreadyMapped`]
+    /// instead of silently replacing it. Intentional replacements have to go through
+    /// [`Self::remap`] or [`Self::remap_with_full`] then.
+    pub fn with_double_map_guard(mut self, double_map_guard: bool) -> Self {
+        self.double_map_guard = double_map_guard;
+        self
+    }
+
+    pub fn double_map_guard(&self) -> bool {
+        self.double_map_guard
+    }
+
+    /// Report every map and unmap to `trace`
+    #[cfg(feature = "trace")]
+    pub fn with_trace(mut self, trace: &'static dyn RmmTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace(&self, event: TraceEvent) {
+        if let Some(trace) = self.trace {
+            trace.event(event);
+        }
+    }
+
+    fn install_flags(&self, flags: PageFlags<A>) -> PageFlags<A> {
+        if self.default_nx && !flags.has_flag(A::ENTRY_FLAG_EXEC_REQUESTED) {
+            flags.execute(false)
+        } else {
+            flags
+        }
+    }
+
+    fn check_protected(&self, range: VirtRange) -> Result<(), MapError> {
+        if self
+            .protected
+            .iter()
+            .any(|protected| protected.overlaps(&range))
+        {
+            Err(MapError::Protected)
+        } else {
+            Ok(())
+        }
+    }
+
     pub unsafe fn create(table_kind: TableKind, mut allocator: F) -> Option<Self> {
         unsafe {
             let table_addr = allocator.allocate_one()?;
@@ -29,6 +402,26 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
         }
     }
 
+    /// Create a mapper over a freshly allocated and zeroed top-level table, with nothing mapped
+    pub unsafe fn new_empty(table_kind: TableKind, mut allocator: F) -> Result<Self, MapError> {
+        unsafe {
+            let table_addr = allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+            A::write_bytes(A::phys_to_virt(table_addr), 0, A::PAGE_SIZE);
+            Ok(Self::new(table_kind, table_addr, allocator))
+        }
+    }
+
+    /// Create a mapper over a top-level table that was built elsewhere, such as by the
+    /// bootloader, marking every table and frame already mapped through it as foreign. Foreign
+    /// frames are never freed into `allocator`, while everything mapped later is owned normally.
+    pub unsafe fn adopt(table_kind: TableKind, root_phys: PhysicalAddress, allocator: F) -> Self {
+        unsafe {
+            let mapper = Self::new(table_kind, root_phys, allocator);
+            mark_foreign_inner(&mut mapper.table());
+            mapper
+        }
+    }
+
     pub unsafe fn current(table_kind: TableKind, allocator: F) -> Self {
         unsafe {
             let table_addr = A::table(table_kind);
@@ -42,6 +435,7 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
 
     pub unsafe fn make_current(&self) {
         unsafe {
+            A::set_table_walk_cached(self.table_kind, !self.table_walk_uncached);
             A::set_table(self.table_kind, self.table_addr);
         }
     }
@@ -60,6 +454,8 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
         &mut self.allocator
     }
 
+    /// Replace the address and flags of the page mapped at `virt` with the result of `f`, with
+    /// break-before-make where the architecture requires it. The returned flush is still needed.
     pub unsafe fn remap_with_full(
         &mut self,
         virt: VirtualAddress,
@@ -73,6 +469,11 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
                 let (new_phys, new_flags) = f(old_phys, old_flags);
                 // TODO: Higher-level PageEntry::new interface?
                 let new_entry = PageEntry::new(new_phys.data(), new_flags.data());
+                if A::needs_break_before_make(old_entry.data(), new_entry.data()) {
+                    // Break: the old entry must be invalid in the TLB before the new one is written
+                    p1.set_entry(i, PageEntry::new(0, 0));
+                    A::invalidate(virt);
+                }
                 p1.set_entry(i, new_entry);
                 Some((old_flags, old_phys, PageFlush::new(virt)))
             })
@@ -98,14 +499,63 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
         unsafe { self.remap_with(virt, |_| flags).map(|(_, _, flush)| flush) }
     }
 
+    /// Change the flags of the page mapped at `virt`, flushing it immediately
+    pub unsafe fn protect(
+        &mut self,
+        virt: VirtualAddress,
+        flags: PageFlags<A>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            self.protect_with_policy(virt, flags, FlushPolicy::Immediate)
+                .map(|_| ())
+        }
+    }
+
+    /// Change the flags of the page mapped at `virt`, handling the flush according to `policy`.
+    /// Returns the flush if it is deferred.
+    pub unsafe fn protect_with_policy(
+        &mut self,
+        virt: VirtualAddress,
+        flags: PageFlags<A>,
+        policy: FlushPolicy,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe {
+            let flush = self.remap(virt, flags).ok_or(MapError::InvalidAddress)?;
+            Ok(policy.apply(flush))
+        }
+    }
+
+    /// Map a newly allocated frame at `virt`, handling the flush according to `policy`. Returns
+    /// the flush if it is deferred.
+    pub unsafe fn map_with_policy(
+        &mut self,
+        virt: VirtualAddress,
+        flags: PageFlags<A>,
+        policy: FlushPolicy,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe { Ok(policy.apply(self.try_map(virt, flags)?)) }
+    }
+
     pub unsafe fn map(
         &mut self,
         virt: VirtualAddress,
         flags: PageFlags<A>,
     ) -> Option<PageFlush<A>> {
+        unsafe { self.try_map(virt, flags).ok() }
+    }
+
+    /// Like [`Self::map`], but reports why the mapping failed
+    pub unsafe fn try_map(
+        &mut self,
+        virt: VirtualAddress,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, MapError> {
         unsafe {
-            let phys = self.allocator.allocate_one()?;
-            self.map_phys(virt, phys, flags)
+            self.check_protected(VirtRange::new(virt, A::PAGE_SIZE))?;
+            let phys = self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+            self.try_map_phys(virt, phys, flags).inspect_err(|_| {
+                self.allocator.free_one(phys);
+            })
         }
     }
 
@@ -115,32 +565,172 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
         phys: PhysicalAddress,
         flags: PageFlags<A>,
     ) -> Option<PageFlush<A>> {
+        unsafe { self.try_map_phys(virt, phys, flags).ok() }
+    }
+
+    /// Like [`Self::map_phys`], but reports why the mapping failed
+    pub unsafe fn try_map_phys(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe {
+            let flags = self.install_flags(flags);
+            self.map_phys_raw(virt, phys, flags)
+        }
+    }
+
+    // Map `virt` to `phys` with exactly `flags`, such as when copying an existing mapping
+    unsafe fn map_phys_raw(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, MapError> {
         unsafe {
+            self.check_protected(VirtRange::new(virt, A::PAGE_SIZE))?;
             //TODO: verify virt and phys are aligned
             //TODO: verify flags have correct bits
-            let entry = PageEntry::new(phys.data(), flags.data());
+            self.map_entry(virt, PageEntry::new(phys.data(), flags.data()), 0)
+        }
+    }
+
+    /// Map a huge page of `level` at `virt` to `phys`, both aligned to its size. On x86_64, level 1
+    /// maps 2 MiB and level 2 maps 1 GiB. Fails with [`MapError::InvalidFlags`] for flags that
+    /// huge entries cannot hold.
+    pub unsafe fn map_huge(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        level: usize,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe {
+            if level == 0 || level > A::MAX_HUGE_LEVEL || level >= A::PAGE_LEVELS {
+                return Err(MapError::InvalidAddress);
+            }
+            let size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            if virt.data() & (size - 1) != 0 || phys.data() & (size - 1) != 0 {
+                return Err(MapError::InvalidAddress);
+            }
+            self.check_protected(VirtRange::new(virt, size))?;
+            if !huge_supports_flags(flags) {
+                return Err(MapError::InvalidFlags);
+            }
+            let flags = self.install_flags(flags).huge(true);
+            self.map_entry(virt, PageEntry::new(phys.data(), flags.data()), level)
+        }
+    }
+
+    unsafe fn map_entry(
+        &mut self,
+        virt: VirtualAddress,
+        entry: PageEntry<A>,
+        level: usize,
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe {
+            // New tables are empty, so the leaf can only be rejected for existing tables
+            let mut table = self.create_tables(virt, level)?;
+            self.map_leaf(&mut table, virt, entry)
+        }
+    }
+
+    /// Walk to the table at `level` containing `virt`, allocating every missing table on the
+    /// way without writing a leaf entry. Tables allocated before a failure are freed again
+    unsafe fn create_tables(
+        &mut self,
+        virt: VirtualAddress,
+        level: usize,
+    ) -> Result<PageTable<A>, MapError> {
+        unsafe {
+            let mut created = None;
+            let result = self.create_tables_inner(virt, level, &mut created);
+            if result.is_err() {
+                self.free_created(virt, created);
+            }
+            result
+        }
+    }
+
+    // Tables allocated before a failure only link to the next new table
+    unsafe fn free_created(
+        &mut self,
+        virt: VirtualAddress,
+        created: Option<(VirtualAddress, PhysicalAddress, usize, usize)>,
+    ) {
+        unsafe {
+            if let Some((base, phys, level, i)) = created {
+                let mut parent = PageTable::<A>::new(base, phys, level);
+                let mut next = parent.next(i);
+                parent.set_entry(i, PageEntry::new(0, 0));
+                while let Some(table) = next {
+                    next = table.index_of(virt).and_then(|j| table.next(j));
+                    self.allocator.free_one(table.phys());
+                }
+            }
+        }
+    }
+
+    unsafe fn map_leaf(
+        &mut self,
+        table: &mut PageTable<A>,
+        virt: VirtualAddress,
+        entry: PageEntry<A>,
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe {
+            let level = table.level();
+            let i = table.index_of(virt).ok_or(MapError::InvalidAddress)?;
+            if let Some(old) = table.entry(i).filter(|old| old.present()) {
+                // Replacing a table would leak everything below it, so only leaves are
+                // left to the guard
+                let is_table = level > 0 && !A::entry_is_huge(old.data(), level);
+                if is_table || self.double_map_guard {
+                    let old = old.address().map_err(|_| MapError::InvalidAddress)?;
+                    return Err(MapError::AlreadyMapped(old));
+                }
+            }
+            table.set_entry(i, entry);
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::Map {
+                virt,
+                phys: PhysicalAddress::new(entry.data() & !A::ENTRY_FLAGS_MASK),
+                flags: entry.flags().data(),
+                level,
+            });
+            Ok(PageFlush::new(virt))
+        }
+    }
+
+    // Records the table and index of the first new table in `created`
+    unsafe fn create_tables_inner(
+        &mut self,
+        virt: VirtualAddress,
+        level: usize,
+        created: &mut Option<(VirtualAddress, PhysicalAddress, usize, usize)>,
+    ) -> Result<PageTable<A>, MapError> {
+        unsafe {
             let mut table = self.table();
             loop {
-                let i = table.index_of(virt)?;
-                if table.level() == 0 {
-                    //TODO: check for overwriting entry
-                    table.set_entry(i, entry);
-                    return Some(PageFlush::new(virt));
+                let i = table.index_of(virt).ok_or(MapError::InvalidAddress)?;
+                if table.level() == level {
+                    return Ok(table);
                 } else {
                     let next_opt = table.next(i);
                     let next = match next_opt {
                         Some(some) => some,
                         None => {
-                            let next_phys = self.allocator.allocate_one()?;
-                            //TODO: correct flags?
-                            let flags = A::ENTRY_FLAG_DEFAULT_TABLE
-                                | if virt.kind() == TableKind::User {
-                                    A::ENTRY_FLAG_TABLE_USER
-                                } else {
-                                    0
-                                };
-                            table.set_entry(i, PageEntry::new(next_phys.data(), flags));
-                            table.next(i)?
+                            if table.entry(i).is_some_and(|entry| entry.present()) {
+                                return Err(MapError::InHugePage);
+                            }
+                            let next_phys =
+                                self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+                            table.set_entry(
+                                i,
+                                PageEntry::new(next_phys.data(), table_flags::<A>(virt)),
+                            );
+                            created.get_or_insert((table.base(), table.phys(), table.level(), i));
+                            table.next(i).ok_or(MapError::InvalidAddress)?
                         }
                     };
                     table = next;
@@ -148,110 +738,2806 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
             }
         }
     }
-    pub unsafe fn map_linearly(
+
+    /// Replace huge entry `i` of `table` with a table of entries one level down mapping the same
+    /// memory with the same flags
+    unsafe fn split_huge(&mut self, table: &mut PageTable<A>, i: usize) -> Result<(), MapError> {
+        unsafe {
+            let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+            let phys = entry.address().map_err(|_| MapError::InvalidAddress)?;
+            let base = table.entry_base(i).ok_or(MapError::InvalidAddress)?;
+            let level = table.level() - 1;
+            let flags = if level == 0 {
+                entry.flags().huge(false)
+            } else {
+                entry.flags()
+            };
+
+            let next_phys = self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+            let mut next = PageTable::<A>::new(base, next_phys, level);
+            for j in 0..A::PAGE_ENTRIES {
+                let offset = j * next.entry_size();
+                next.set_entry(j, PageEntry::new(phys.add(offset).data(), flags.data()));
+            }
+            table.set_entry(i, PageEntry::new(next_phys.data(), table_flags::<A>(base)));
+            Ok(())
+        }
+    }
+    /// Map `virt` to `phys` with the accessed flag cleared, so that the first access faults on
+    /// aarch64, see [`Self::handle_af_fault`]
+    pub unsafe fn map_af_tracked(
         &mut self,
+        virt: VirtualAddress,
         phys: PhysicalAddress,
         flags: PageFlags<A>,
-    ) -> Option<(VirtualAddress, PageFlush<A>)> {
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe { self.try_map_phys(virt, phys, flags.accessed(false)) }
+    }
+
+    /// Map `virt` to `phys` with the accessed and dirty flags cleared, so that references and
+    /// writes can be tracked with `tracking`. With [`AccessTracking::Fault`], writable pages are
+    /// mapped read-only, so that the first write faults as well.
+    pub unsafe fn map_tracked(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: PageFlags<A>,
+        tracking: AccessTracking,
+    ) -> Result<PageFlush<A>, MapError> {
         unsafe {
-            let virt = A::phys_to_virt(phys);
-            self.map_phys(virt, phys, flags).map(|flush| (virt, flush))
+            let flags = flags.accessed(false).dirty(false);
+            match tracking {
+                AccessTracking::Scan => self.try_map_phys(virt, phys, flags),
+                AccessTracking::Fault => self.try_map_phys(virt, phys, flags.write(false)),
+            }
         }
     }
-    fn visit<T>(
-        &self,
+
+    /// Atomically replace the base page at `virt` with a non-present entry holding `token`, and
+    /// flush it on this core. Returns the frame, which is not freed, and whether it was dirty.
+    pub unsafe fn swap_out(
+        &mut self,
         virt: VirtualAddress,
-        f: impl FnOnce(&mut PageTable<A>, usize) -> T,
-    ) -> Option<T> {
-        let mut table = self.table();
+        token: u64,
+    ) -> Result<(PhysicalAddress, bool), MapError> {
         unsafe {
-            loop {
-                let i = table.index_of(virt)?;
-                if table.level() == 0 {
-                    return Some(f(&mut table, i));
-                } else {
-                    table = table.next(i)?;
-                }
+            if token >> SWAP_TOKEN_BITS != 0 {
+                return Err(MapError::SwapTokenTooLarge);
             }
+            let swapped = swap_entry::<A>(token);
+            let (phys, flags) = self
+                .visit(virt, |p1, i| {
+                    let mut old_entry = p1.entry(i)?;
+                    loop {
+                        let old_phys = old_entry.address().ok()?;
+                        match p1.compare_set_entry(i, old_entry, swapped)? {
+                            Ok(()) => return Some((old_phys, old_entry.flags())),
+                            // Changed since it was read, such as by the MMU setting its flags
+                            Err(entry) => old_entry = entry,
+                        }
+                    }
+                })
+                .flatten()
+                .ok_or(MapError::InvalidAddress)?;
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::Unmap { virt, phys });
+            PageFlush::<A>::new(virt).flush();
+            Ok((phys, flags.has_dirty()))
         }
     }
-    pub fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>)> {
-        let entry = self.visit(virt, |p1, i| unsafe { p1.entry(i) })??;
-        Some((entry.address().ok()?, entry.flags()))
+
+    /// Token recorded by [`Self::swap_out`] for the base page at `virt`, if it is swapped out
+    pub fn swap_token(&self, virt: VirtualAddress) -> Option<u64> {
+        unsafe {
+            self.visit(virt, |p1, i| entry_swap_token(p1.entry(i)?))
+                .flatten()
+        }
     }
 
-    pub unsafe fn unmap(
+    /// Move the base page at `virt` to `new_phys`, copying its contents. Returns the old frame,
+    /// which is not freed.
+    pub unsafe fn migrate(
         &mut self,
         virt: VirtualAddress,
-        unmap_parents: bool,
+        new_phys: PhysicalAddress,
+    ) -> Option<(PhysicalAddress, PageFlush<A>)> {
+        unsafe {
+            self.remap_with_full(virt, |old_phys, flags| {
+                let (src, dst) = (A::phys_to_virt(old_phys), A::phys_to_virt(new_phys));
+                for offset in (0..A::PAGE_SIZE).step_by(core::mem::size_of::<usize>()) {
+                    A::write(dst.add(offset), A::read::<usize>(src.add(offset)));
+                }
+                (new_phys, flags)
+            })
+            .map(|(_, old_phys, flush)| (old_phys, flush))
+        }
+    }
+
+    /// Set the accessed flag of `virt` after an access flag fault, passing its frame to `record`
+    pub unsafe fn handle_af_fault(
+        &mut self,
+        virt: VirtualAddress,
+        record: impl FnOnce(PhysicalAddress),
     ) -> Option<PageFlush<A>> {
         unsafe {
-            let (old, _, flush) = self.unmap_phys(virt, unmap_parents)?;
-            self.allocator.free_one(old);
+            let (_, phys, flush) = self.remap_with(virt, |flags| flags.accessed(true))?;
+            record(phys);
             Some(flush)
         }
     }
 
-    pub unsafe fn unmap_phys(
+    /// Atomically set the dirty and writable flags of `virt` after a write fault. Fails with
+    /// [`MapError::AlreadyHandled`] if another core already did.
+    pub unsafe fn fault_set_dirty_writable(
         &mut self,
         virt: VirtualAddress,
-        unmap_parents: bool,
-    ) -> Option<(PhysicalAddress, PageFlags<A>, PageFlush<A>)> {
+    ) -> Result<PageFlush<A>, MapError> {
         unsafe {
-            //TODO: verify virt is aligned
-            let mut table = self.table();
-            let level = table.level();
-            unmap_phys_inner(virt, &mut table, level, unmap_parents, &mut self.allocator)
-                .map(|(pa, pf)| (pa, pf, PageFlush::new(virt)))
+            self.visit(virt, |p1, i| {
+                let mut old_entry = p1.entry(i).ok_or(MapError::InvalidAddress)?;
+                loop {
+                    if !old_entry.present() {
+                        return Err(MapError::InvalidAddress);
+                    }
+                    let new_flags = old_entry.flags().write(true).dirty(true);
+                    if new_flags.data() == old_entry.flags().data() {
+                        return Err(MapError::AlreadyHandled);
+                    }
+                    let old_phys = old_entry.address().map_err(|_| MapError::InvalidAddress)?;
+                    let new_entry = PageEntry::new(old_phys.data(), new_flags.data());
+                    match p1.compare_set_entry(i, old_entry, new_entry) {
+                        Some(Ok(())) => return Ok(PageFlush::new(virt)),
+                        // Changed since it was read, check it again
+                        Some(Err(entry)) => old_entry = entry,
+                        None => return Err(MapError::InvalidAddress),
+                    }
+                }
+            })
+            .unwrap_or(Err(MapError::InvalidAddress))
         }
     }
-}
-unsafe fn unmap_phys_inner<A: Arch>(
-    virt: VirtualAddress,
-    table: &mut PageTable<A>,
-    initial_level: usize,
-    unmap_parents: bool,
-    allocator: &mut impl FrameAllocator,
-) -> Option<(PhysicalAddress, PageFlags<A>)> {
-    unsafe {
-        let i = table.index_of(virt)?;
 
-        if table.level() == 0 {
-            let entry_opt = table.entry(i);
-            table.set_entry(i, PageEntry::new(0, 0));
-            let entry = entry_opt?;
+    /// Map `count` newly allocated frames contiguously at `virt`, writing the coalesced physical
+    /// segments to `sg_out`. Returns the number of segments, and undoes everything on failure.
+    pub unsafe fn alloc_map_sg(
+        &mut self,
+        virt: VirtualAddress,
+        count: usize,
+        flags: PageFlags<A>,
+        sg_out: &mut [(PhysicalAddress, usize)],
+    ) -> Result<usize, MapError> {
+        unsafe {
+            let mut segments = 0;
+            for i in 0..count {
+                let page_virt = virt.add(i * A::PAGE_SIZE);
+                let result = match self.allocator.allocate_one() {
+                    Some(phys) => {
+                        let extends = segments > 0 && {
+                            let (base, size) = sg_out[segments - 1];
+                            base.add(size) == phys
+                        };
+                        if !extends && segments == sg_out.len() {
+                            self.allocator.free_one(phys);
+                            Err(MapError::TooManySegments)
+                        } else {
+                            match self.try_map_phys(page_virt, phys, flags) {
+                                Ok(flush) => {
+                                    flush.flush();
+                                    if extends {
+                                        sg_out[segments - 1].1 += A::PAGE_SIZE;
+                                    } else {
+                                        sg_out[segments] = (phys, A::PAGE_SIZE);
+                                        segments += 1;
+                                    }
+                                    Ok(())
+                                }
+                                Err(err) => {
+                                    self.allocator.free_one(phys);
+                                    Err(err)
+                                }
+                            }
+                        }
+                    }
+                    None => Err(MapError::OutOfFrames),
+                };
 
-            Some((entry.address().ok()?, entry.flags()))
-        } else {
-            let mut subtable = table.next(i)?;
+                if let Err(err) = result {
+                    for j in 0..i {
+                        if let Some(flush) = self.unmap(virt.add(j * A::PAGE_SIZE), true) {
+                            flush.flush();
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+            Ok(segments)
+        }
+    }
 
-            let res =
-                unmap_phys_inner(virt, &mut subtable, initial_level, unmap_parents, allocator)?;
+    /// Map `count` pages at `virt` to the frames at `phys`, using huge pages where `options`
+    /// allows. On failure, the pages mapped so far are unmapped again.
+    pub unsafe fn map_range(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        count: usize,
+        flags: PageFlags<A>,
+        options: MapOptions,
+        mut flusher: impl Flusher<A>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            let size = count * A::PAGE_SIZE;
+            self.check_protected(VirtRange::new(virt, size))?;
+            let chunk = |offset: usize| {
+                if options.allow_huge && huge_supports_flags(flags) {
+                    chunk_for_mapping::<A>(virt.add(offset), phys.add(offset), size - offset)
+                } else {
+                    0
+                }
+            };
 
-            //TODO: This is a bad idea for architectures where the kernel mappings are done in the process tables,
-            // as these mappings may become out of sync
-            if unmap_parents {
-                // TODO: Use a counter? This would reduce the remaining number of available bits, but could be
-                // faster (benchmark is needed).
-                let is_still_populated = (0..A::PAGE_ENTRIES)
-                    .map(|j| subtable.entry(j).expect("must be within bounds"))
-                    .any(|e| e.present());
+            let mut offset = 0;
+            while offset < size {
+                let level = chunk(offset);
+                let result = if level == 0 {
+                    self.try_map_phys(virt.add(offset), phys.add(offset), flags)
+                } else {
+                    self.map_huge(virt.add(offset), phys.add(offset), level, flags)
+                };
+                match result {
+                    Ok(flush) => flusher.consume(flush),
+                    Err(err) => {
+                        // Chunking is deterministic, so the same chunks are unmapped again
+                        let mapped = offset;
+                        let mut offset = 0;
+                        while offset < mapped {
+                            let level = chunk(offset);
+                            let page_size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+                            if level == 0 {
+                                if let Some((_, _, flush)) = self.unmap_phys(virt.add(offset), true)
+                                {
+                                    flusher.consume(flush);
+                                }
+                            } else {
+                                let _ = self.unmap_range(
+                                    virt.add(offset),
+                                    page_size / A::PAGE_SIZE,
+                                    &mut flusher,
+                                    |_, _| {},
+                                );
+                            }
+                            offset += page_size;
+                        }
+                        return Err(err);
+                    }
+                }
+                offset += 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            }
+            Ok(())
+        }
+    }
+
+    /// Map `count` pages to the frames at `phys` between two unmapped guard pages, so each call
+    /// takes two pages of address space more than it maps: `count + 2` pages at `virt`. Returns
+    /// the base of the mapped pages, or [`MapError::AlreadyMapped`] if either guard is mapped.
+    pub unsafe fn map_with_guards(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        count: usize,
+        flags: PageFlags<A>,
+        flusher: impl Flusher<A>,
+    ) -> Result<VirtualAddress, MapError> {
+        unsafe {
+            let base = virt.add(A::PAGE_SIZE);
+            for guard in [virt, base.add(count * A::PAGE_SIZE)] {
+                if let Some((old, _)) = self.translate(guard) {
+                    return Err(MapError::AlreadyMapped(old));
+                }
+            }
+            self.map_range(base, phys, count, flags, MapOptions::default(), flusher)?;
+            Ok(base)
+        }
+    }
+
+    /// Map `count` pages to the frames starting at `phys`, cycling through the permissions of
+    /// `pattern` page by page, such as to test fault handling. Pages with [`Permissions::None`]
+    /// are left unmapped, and on failure, the pages mapped so far stay mapped.
+    pub unsafe fn map_pattern(
+        &mut self,
+        virt: VirtualAddress,
+        count: usize,
+        pattern: &[Permissions],
+        phys: PhysicalAddress,
+        mut flusher: impl Flusher<A>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            for (i, permissions) in pattern.iter().cycle().take(count).enumerate() {
+                if let Some(flags) = permissions.flags() {
+                    let offset = i * A::PAGE_SIZE;
+                    flusher.consume(self.try_map_phys(
+                        virt.add(offset),
+                        phys.add(offset),
+                        flags,
+                    )?);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Reserve `slots` pages at `base` for [`Self::with_temp_map`], allocating their tables up
+    /// front. The pool must only be used on the current core.
+    pub unsafe fn install_scratch_region(
+        &mut self,
+        base: VirtualAddress,
+        slots: usize,
+    ) -> Result<ScratchPool<A>, MapError> {
+        assert!(slots <= SCRATCH_POOL_MAX_SLOTS);
+        unsafe {
+            for i in 0..slots {
+                if let Some((phys, _)) = self.translate(base.add(i * A::PAGE_SIZE)) {
+                    return Err(MapError::AlreadyMapped(phys));
+                }
+            }
+            let mut created = [None; SCRATCH_POOL_MAX_SLOTS];
+            for i in 0..slots {
+                let virt = base.add(i * A::PAGE_SIZE);
+                if let Err(err) = self.create_tables_inner(virt, 0, &mut created[i]) {
+                    // Later slots may hang tables off the ones created for earlier slots
+                    for j in (0..=i).rev() {
+                        self.free_created(base.add(j * A::PAGE_SIZE), created[j]);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(ScratchPool {
+            base,
+            slots,
+            used: Cell::new(0),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Map `phys` with `flags` at a free slot of `pool` while `f` runs, passing it the mapped
+    /// address. Temporary maps can be nested, each one takes its own slot.
+    pub unsafe fn with_temp_map<T>(
+        &mut self,
+        pool: &ScratchPool<A>,
+        phys: PhysicalAddress,
+        flags: PageFlags<A>,
+        f: impl FnOnce(&mut Self, VirtualAddress) -> T,
+    ) -> Result<T, MapError> {
+        unsafe {
+            let slot = pool.claim().ok_or(MapError::NoScratchSlot)?;
+            let virt = pool.base.add(slot * A::PAGE_SIZE);
+            match self.try_map_phys(virt, phys, flags) {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    pool.release(slot);
+                    return Err(err);
+                }
+            }
+            let result = f(self, virt);
+            if let Some((_, _, flush)) = self.unmap_phys(virt, false) {
+                flush.flush();
+            }
+            pool.release(slot);
+            Ok(result)
+        }
+    }
+
+    /// Map all of `areas` into the physmap with the pages planned by [`plan_physmap`]. On
+    /// failure, the pages mapped so far stay mapped.
+    pub unsafe fn map_physmap(
+        &mut self,
+        areas: &[MemoryArea],
+        flags: PageFlags<A>,
+        mut flusher: impl Flusher<A>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            for (phys, virt, page_size) in physmap_pages::<A>(areas) {
+                let flush = if page_size == A::PAGE_SIZE {
+                    self.try_map_phys(virt, phys, flags)?
+                } else {
+                    let level =
+                        (page_size.trailing_zeros() as usize - A::PAGE_SHIFT) / A::PAGE_ENTRY_SHIFT;
+                    self.map_huge(virt, phys, level, flags)?
+                };
+                flusher.consume(flush);
+            }
+            Ok(())
+        }
+    }
+
+    pub unsafe fn map_linearly(
+        &mut self,
+        phys: PhysicalAddress,
+        flags: PageFlags<A>,
+    ) -> Option<(VirtualAddress, PageFlush<A>)> {
+        unsafe {
+            let virt = A::phys_to_virt(phys);
+            self.map_phys(virt, phys, flags).map(|flush| (virt, flush))
+        }
+    }
+    fn visit<T>(
+        &self,
+        virt: VirtualAddress,
+        f: impl FnOnce(&mut PageTable<A>, usize) -> T,
+    ) -> Option<T> {
+        let mut table = self.table();
+        unsafe {
+            loop {
+                let i = table.index_of(virt)?;
+                if table.level() == 0 {
+                    return Some(f(&mut table, i));
+                } else {
+                    table = table.next(i)?;
+                }
+            }
+        }
+    }
+    pub fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>)> {
+        let mut table = self.table();
+        unsafe {
+            loop {
+                let i = table.index_of(virt)?;
+                let entry = table.entry(i)?;
+                if table.level() == 0 || A::entry_is_huge(entry.data(), table.level()) {
+                    // Base page of virt inside a huge page
+                    let offset = virt.data() & (table.entry_size() - 1) & !A::PAGE_OFFSET_MASK;
+                    return Some((entry.address().ok()?.add(offset), entry.flags()));
+                }
+                table = table.next(i)?;
+            }
+        }
+    }
 
-                if !is_still_populated {
-                    allocator.free_one(subtable.phys());
+    /// Yield the addresses of `addrs` whose software translation differs from the MMU's, such as
+    /// because of a missing flush. Never yields anything without `HW_TRANSLATE`.
+    pub fn audit_translations<'a>(
+        &'a self,
+        addrs: &'a [VirtualAddress],
+    ) -> impl Iterator<
+        Item = (
+            VirtualAddress,
+            Option<PhysicalAddress>,
+            Option<PhysicalAddress>,
+        ),
+    > + 'a {
+        let addrs = if A::HW_TRANSLATE { addrs } else { &[] };
+        addrs.iter().filter_map(move |&virt| {
+            let software = self
+                .translate(virt)
+                .map(|(phys, _)| phys.add(virt.data() & A::PAGE_OFFSET_MASK));
+            let hardware = unsafe { A::hw_translate(virt) };
+            (software != hardware).then_some((virt, software, hardware))
+        })
+    }
+
+    /// Flags of the page mapped at `virt`
+    pub fn translate_flags(&self, virt: VirtualAddress) -> Option<PageFlags<A>> {
+        self.translate(virt).map(|(_, flags)| flags)
+    }
+
+    /// Walk the tables that translate `virt`, yielding the entry used at each level
+    pub fn walk(&self, virt: VirtualAddress) -> Walk<A> {
+        unsafe { Walk::new(self.table(), virt) }
+    }
+
+    /// Iterate over all mapped pages
+    pub fn iter(&self) -> PageIter<A> {
+        unsafe { PageIter::new(&self.table(), A::PAGE_ENTRIES) }
+    }
+
+    /// Iterate over all mapped ranges, merging consecutive pages that map contiguous frames with
+    /// the same flags, which keeps dumps of linear mappings short
+    pub fn iter_ranges(&self) -> RangeIter<A> {
+        RangeIter::new(self.iter())
+    }
+
+    /// Call `f` with the ranges of [`Self::iter_ranges`], stopping after `max` ranges or when `f`
+    /// breaks, so that the output stays bounded even for a fully mapped address space. Returns
+    /// whether there were ranges left when it stopped.
+    pub fn iter_ranges_bounded(
+        &self,
+        max: usize,
+        mut f: impl FnMut(VirtRange, PhysicalAddress, PageFlags<A>) -> ControlFlow<()>,
+    ) -> bool {
+        let mut ranges = self.iter_ranges();
+        for (range, phys, flags) in ranges.by_ref().take(max) {
+            if f(range, phys, flags).is_break() {
+                break;
+            }
+        }
+        ranges.next().is_some()
+    }
+
+    /// Tally the mapped bytes by page size, which shows how much of the address space is mapped
+    /// with huge pages
+    pub fn size_breakdown(&self) -> SizeBreakdown {
+        let mut breakdown = SizeBreakdown::default();
+        for (.., size) in self.iter() {
+            let level = (size.trailing_zeros() as usize - A::PAGE_SHIFT) / A::PAGE_ENTRY_SHIFT;
+            breakdown.by_level[level] += size;
+        }
+        breakdown
+    }
+
+    /// Find the first virtual address, in address order, that maps `phys`. This walks the entire
+    /// tree, so it is only meant for rare paths like handling errors of a specific frame.
+    pub fn find_virt_for_phys(&self, phys: PhysicalAddress) -> Option<VirtualAddress> {
+        self.iter().find_map(|(virt, base, _, size)| {
+            let offset = phys.data().checked_sub(base.data())?;
+            (offset < size).then(|| virt.add(offset))
+        })
+    }
+
+    /// Iterate over the mapped pages in the lower half of the address space, without descending
+    /// into the kernel half
+    pub fn iter_user(
+        &self,
+    ) -> impl Iterator<Item = (VirtualAddress, PhysicalAddress, PageFlags<A>, usize)> {
+        unsafe { PageIter::new(&self.table(), A::PAGE_ENTRIES / 2) }
+    }
+
+    /// Unmap the lower half, freeing its tables into the allocator of this mapper and its frames
+    /// into `allocator`. Foreign frames are kept, swap tokens are passed to `swapped` with their
+    /// page.
+    pub unsafe fn unmap_user_all(
+        &mut self,
+        allocator: &mut impl FrameAllocator,
+        mut swapped: impl FnMut(VirtualAddress, u64),
+    ) -> PageFlushAll<A> {
+        unsafe {
+            let mut table = self.table();
+            for i in 0..A::PAGE_ENTRIES / 2 {
+                if table.entry(i).is_some_and(|entry| entry.present()) {
+                    free_entry_inner(
+                        &table,
+                        i,
+                        &mut self.allocator,
+                        &mut |_, address, count| allocator.free(address, count),
+                        &mut swapped,
+                    );
                     table.set_entry(i, PageEntry::new(0, 0));
                 }
             }
+            PageFlushAll::new()
+        }
+    }
 
-            Some(res)
+    /// Clear the write flag of every writable user page in the lower half, so that the next write
+    /// to any of them faults, such as to start a snapshot with copy-on-write. Huge pages are
+    /// write-protected as a whole. Returns a flush for all of them.
+    pub unsafe fn write_protect_all_user(&mut self) -> PageFlushAll<A> {
+        unsafe {
+            write_protect_user_inner(&mut self.table(), A::PAGE_ENTRIES / 2);
+            PageFlushAll::new()
+        }
+    }
+
+    /// Unmap everything below top-level entry `top_index`, freeing its tables, and its frames into
+    /// `allocator` if `free_leaves` is set. Swap tokens are passed to `swapped` with their page.
+    /// Fails with [`MapError::Protected`] for tables shared by [`Self::share_kernel_half`].
+    pub unsafe fn free_subtree(
+        &mut self,
+        top_index: usize,
+        allocator: &mut impl FrameAllocator,
+        free_leaves: bool,
+        mut swapped: impl FnMut(VirtualAddress, u64),
+    ) -> Result<PageFlushAll<A>, MapError> {
+        unsafe {
+            let mut table = self.table();
+            let entry = table.entry(top_index).ok_or(MapError::InvalidAddress)?;
+            if entry.flags().has_foreign() {
+                return Err(MapError::Protected);
+            }
+            if entry.present() {
+                free_entry_inner(
+                    &table,
+                    top_index,
+                    &mut self.allocator,
+                    &mut |_, address, count| {
+                        if free_leaves {
+                            allocator.free(address, count);
+                        }
+                    },
+                    &mut swapped,
+                );
+                table.set_entry(top_index, PageEntry::new(0, 0));
+            }
+            Ok(PageFlushAll::new())
+        }
+    }
+
+    /// Copy the top-level entries of the kernel half from `shared`, marked foreign. Only tables
+    /// that already exist in `shared` are shared, so they should all be allocated at boot.
+    pub unsafe fn share_kernel_half<F2: FrameAllocator>(&mut self, shared: &PageMapper<A, F2>) {
+        unsafe {
+            let shared_table = shared.table();
+            let mut table = self.table();
+            for i in A::PAGE_ENTRIES / 2..A::PAGE_ENTRIES {
+                let mut entry = shared_table.entry(i).expect("must be within bounds");
+                if entry.present() {
+                    entry.set_flags(entry.flags().foreign(true));
+                }
+                table.set_entry(i, entry);
+            }
+        }
+    }
+
+    /// Check that no table is reachable from both the user and the kernel half, walking each
+    /// half once. The tables of the user half are collected into `user_tables`.
+    pub fn check_half_isolation(
+        &self,
+        user_tables: &mut [PhysicalAddress],
+    ) -> Result<(), IsolationError> {
+        let table = self.table();
+        let half = A::PAGE_ENTRIES / 2;
+        unsafe {
+            let mut count = 0;
+            for_each_table(&table, 0..half, &mut |user| {
+                *user_tables
+                    .get_mut(count)
+                    .ok_or(IsolationError::BufferTooSmall)? = user;
+                count += 1;
+                Ok(())
+            })?;
+            let user_tables = &mut user_tables[..count];
+            user_tables.sort_unstable();
+            for_each_table(
+                &table,
+                half..A::PAGE_ENTRIES,
+                &mut |kernel| match user_tables.binary_search(&kernel) {
+                    Ok(_) => Err(IsolationError::Aliased(kernel)),
+                    Err(_) => Ok(()),
+                },
+            )
+        }
+    }
+
+    /// Check if the code that is currently running is mapped by this table, which should be
+    /// checked before tearing down mappings that could include it
+    pub fn contains_current_ip(&self) -> bool {
+        self.contains_ip(current_ip())
+    }
+
+    /// Check if the page of the instruction pointer `ip` is mapped by this table
+    pub fn contains_ip(&self, ip: VirtualAddress) -> bool {
+        self.translate(ip).is_some()
+    }
+
+    /// Find the top-level slots with present entries by reading the top-level table once, so
+    /// empty slots can be skipped when comparing or copying address spaces
+    pub fn top_level_populated(&self) -> TopLevelSet {
+        assert!(A::PAGE_ENTRIES <= MAX_TOP_ENTRIES);
+        let table = self.table();
+        let mut populated = TopLevelSet::empty();
+        for i in 0..A::PAGE_ENTRIES {
+            if unsafe { table.entry(i) }.is_some_and(|entry| entry.present()) {
+                populated.insert(i);
+            }
+        }
+        populated
+    }
+
+    /// Fold every present entry of the table tree into a hash, to detect unexpected changes
+    pub fn checksum(&self) -> u64 {
+        unsafe { checksum_inner(&self.table(), FNV_OFFSET_BASIS) }
+    }
+
+    /// Check that `virt` is mapped by a single huge page in a table of `level`, which covers
+    /// the whole surrounding region of that page size
+    pub fn is_huge_mapped(&self, virt: VirtualAddress, level: usize) -> bool {
+        let mut table = self.table();
+        unsafe {
+            while table.level() > level {
+                let Some(next) = table.index_of(virt).and_then(|i| table.next(i)) else {
+                    return false;
+                };
+                table = next;
+            }
+            level > 0
+                && table.level() == level
+                && table
+                    .index_of(virt)
+                    .and_then(|i| table.entry(i))
+                    .is_some_and(|entry| A::entry_is_huge(entry.data(), level))
+        }
+    }
+
+    pub unsafe fn unmap(
+        &mut self,
+        virt: VirtualAddress,
+        unmap_parents: bool,
+    ) -> Option<PageFlush<A>> {
+        unsafe {
+            let (old, flags, flush) = self.unmap_phys(virt, unmap_parents)?;
+            if !flags.has_foreign() {
+                self.allocator.free_one(old);
+            }
+            Some(flush)
+        }
+    }
+
+    /// Unmap the page at `virt` and free its frame, handling the flush according to `policy`.
+    /// Returns the flush if it is deferred.
+    pub unsafe fn unmap_with_policy(
+        &mut self,
+        virt: VirtualAddress,
+        unmap_parents: bool,
+        policy: FlushPolicy,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe {
+            let flush = self
+                .unmap(virt, unmap_parents)
+                .ok_or(MapError::InvalidAddress)?;
+            Ok(policy.apply(flush))
+        }
+    }
+
+    /// Unmap `count` pages at `virt`, splitting partially covered huge pages and freeing tables
+    /// left empty, except for shared kernel ones. Like [`Self::unmap_phys`], frames are not freed.
+    /// Swap tokens in the range are removed and passed to `swapped` with their page.
+    pub unsafe fn unmap_range(
+        &mut self,
+        virt: VirtualAddress,
+        count: usize,
+        mut flusher: impl Flusher<A>,
+        mut swapped: impl FnMut(VirtualAddress, u64),
+    ) -> Result<(), MapError> {
+        unsafe {
+            //TODO: verify virt is aligned
+            let end = virt.add(count * A::PAGE_SIZE);
+            let mut page = virt;
+            while page < end {
+                let mut table = self.table();
+                loop {
+                    let i = table.index_of(page).ok_or(MapError::InvalidAddress)?;
+                    let size = table.entry_size();
+                    let base = VirtualAddress::new(page.data() & !(size - 1));
+                    let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                    if !entry.present() {
+                        if let Some(token) = entry_swap_token(entry) {
+                            table.set_entry(i, PageEntry::new(0, 0));
+                            swapped(base, token);
+                        }
+                        page = base.add(size);
+                        break;
+                    }
+
+                    if table.level() == 0 || A::entry_is_huge(entry.data(), table.level()) {
+                        if page == base && base.add(size) <= end {
+                            table.set_entry(i, PageEntry::new(0, 0));
+                            #[cfg(feature = "trace")]
+                            if let Ok(phys) = entry.address() {
+                                self.trace(TraceEvent::Unmap { virt: base, phys });
+                            }
+                            for offset in (0..size).step_by(A::PAGE_SIZE) {
+                                flusher.consume(PageFlush::new(base.add(offset)));
+                            }
+                            page = base.add(size);
+                            break;
+                        }
+                        self.split_huge(&mut table, i)?;
+                        flusher.consume(PageFlush::new(base));
+                    }
+                    table = table.next(i).ok_or(MapError::InvalidAddress)?;
+                }
+            }
+
+            let range = VirtRange::new(
+                VirtualAddress::new(virt.data() & A::PAGE_ADDRESS_MASK),
+                count * A::PAGE_SIZE,
+            );
+            free_empty_tables(&mut self.table(), &range, &mut self.allocator);
+            Ok(())
+        }
+    }
+
+    /// Move the mappings of `size` bytes at `old_base` to the free, disjoint range at `new_base`,
+    /// without freeing frames. Undone on failure. Huge pages are moved as base pages.
+    pub unsafe fn relocate_kernel(
+        &mut self,
+        old_base: VirtualAddress,
+        new_base: VirtualAddress,
+        size: usize,
+    ) -> Result<(), MapError> {
+        unsafe {
+            let count = size.div_ceil(A::PAGE_SIZE);
+            let span = count * A::PAGE_SIZE;
+            if VirtRange::new(old_base, span).overlaps(&VirtRange::new(new_base, span)) {
+                return Err(MapError::InvalidAddress);
+            }
+            for i in 0..count {
+                if let Some((phys, _)) = self.translate(new_base.add(i * A::PAGE_SIZE)) {
+                    return Err(MapError::AlreadyMapped(phys));
+                }
+            }
+
+            let mut flusher = PageFlushAll::new();
+            let mut executable = false;
+            for i in 0..count {
+                let Some((phys, flags)) = self.translate(old_base.add(i * A::PAGE_SIZE)) else {
+                    continue;
+                };
+                executable |= flags.has_execute();
+                // The huge bit may also have a meaning for base pages, such as write combining
+                let from_huge = self
+                    .walk(old_base.add(i * A::PAGE_SIZE))
+                    .last()
+                    .is_some_and(|step| A::entry_is_huge(step.entry.data(), step.level));
+                let flags = if from_huge { flags.huge(false) } else { flags };
+                let new_virt = new_base.add(i * A::PAGE_SIZE);
+                match self.map_phys_raw(new_virt, phys, flags) {
+                    Ok(flush) => flusher.consume(flush),
+                    Err(err) => {
+                        // Only the pages that are mapped at the old address were mapped here
+                        for j in 0..i {
+                            if self.translate(old_base.add(j * A::PAGE_SIZE)).is_some() {
+                                self.unmap_range(
+                                    new_base.add(j * A::PAGE_SIZE),
+                                    1,
+                                    &mut flusher,
+                                    |_, _| {},
+                                )?;
+                            }
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            // Kernel pages are never swapped out
+            self.unmap_range(old_base, count, &mut flusher, |_, _| {})?;
+            drop(flusher);
+
+            if executable {
+                A::invalidate_icache(new_base, span);
+            }
+            Ok(())
+        }
+    }
+
+    pub unsafe fn unmap_phys(
+        &mut self,
+        virt: VirtualAddress,
+        unmap_parents: bool,
+    ) -> Option<(PhysicalAddress, PageFlags<A>, PageFlush<A>)> {
+        unsafe {
+            //TODO: verify virt is aligned
+            let mut table = self.table();
+            let level = table.level();
+            let (phys, flags) =
+                unmap_phys_inner(virt, &mut table, level, unmap_parents, &mut self.allocator)?;
+            #[cfg(feature = "trace")]
+            self.trace(TraceEvent::Unmap { virt, phys });
+            Some((phys, flags, PageFlush::new(virt)))
         }
     }
 }
-impl<A, F: core::fmt::Debug> core::fmt::Debug for PageMapper<A, F> {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("PageMapper")
-            .field("frame", &self.table_addr)
+/// Address of code that is running right now, which is this function
+#[inline(never)]
+fn current_ip() -> VirtualAddress {
+    VirtualAddress::new(current_ip as usize)
+}
+
+fn table_flags<A: Arch>(virt: VirtualAddress) -> usize {
+    //TODO: correct flags?
+    A::ENTRY_FLAG_DEFAULT_TABLE
+        | if virt.kind() == TableKind::User {
+            A::ENTRY_FLAG_TABLE_USER
+        } else {
+            0
+        }
+}
+
+// Free the tables below entry `i` of `table` into `allocator`, passing each leaf to `free_leaf`
+// and each swap token to `swapped`
+unsafe fn free_entry_inner<A: Arch, T: FrameAllocator>(
+    table: &PageTable<A>,
+    i: usize,
+    allocator: &mut T,
+    free_leaf: &mut impl FnMut(&mut T, PhysicalAddress, FrameCount),
+    swapped: &mut impl FnMut(VirtualAddress, u64),
+) {
+    unsafe {
+        let Some(entry) = table.entry(i) else {
+            return;
+        };
+        let Ok(address) = entry.address() else {
+            if let (Some(token), Some(virt)) = (entry_swap_token(entry), table.entry_base(i)) {
+                swapped(virt, token);
+            }
+            return;
+        };
+        if let Some(next) = table.next(i) {
+            for j in 0..A::PAGE_ENTRIES {
+                free_entry_inner(&next, j, allocator, free_leaf, swapped);
+            }
+            if !entry.flags().has_foreign() {
+                allocator.free_one(next.phys());
+            }
+        } else if !entry.flags().has_foreign() {
+            free_leaf(
+                allocator,
+                address,
+                FrameCount::new(table.entry_size() / A::PAGE_SIZE),
+            );
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(mut hash: u64, value: u64) -> u64 {
+    for byte in value.to_le_bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+unsafe fn checksum_inner<A: Arch>(table: &PageTable<A>, mut hash: u64) -> u64 {
+    unsafe {
+        for i in 0..A::PAGE_ENTRIES {
+            let entry = table.entry(i).expect("must be within bounds");
+            if !entry.present() {
+                continue;
+            }
+            hash = fnv1a(hash, ((table.level() as u64) << 32) | i as u64);
+            hash = fnv1a(hash, entry.data() as u64);
+            if let Some(next) = table.next(i) {
+                hash = checksum_inner(&next, hash);
+            }
+        }
+        hash
+    }
+}
+
+unsafe fn write_protect_user_inner<A: Arch>(table: &mut PageTable<A>, end: usize) {
+    unsafe {
+        for i in 0..end {
+            let mut entry = table.entry(i).expect("must be within bounds");
+            if !entry.present() {
+                continue;
+            }
+            if let Some(mut next) = table.next(i) {
+                write_protect_user_inner(&mut next, A::PAGE_ENTRIES);
+                continue;
+            }
+            // Retry if the entry changed since it was read, so that a dirty flag set by the
+            // hardware in between is not lost
+            while entry.flags().has_user() && entry.flags().has_write() {
+                let mut new_entry = entry;
+                new_entry.set_flags(entry.flags().write(false));
+                match table.compare_set_entry(i, entry, new_entry) {
+                    Some(Err(current)) => entry = current,
+                    _ => break,
+                }
+            }
+        }
+    }
+}
+
+// Mark every present entry below `table` as foreign
+unsafe fn mark_foreign_inner<A: Arch>(table: &mut PageTable<A>) {
+    unsafe {
+        for i in 0..A::PAGE_ENTRIES {
+            let mut entry = table.entry(i).expect("must be within bounds");
+            if !entry.present() {
+                continue;
+            }
+            if let Some(mut next) = table.next(i) {
+                mark_foreign_inner(&mut next);
+            }
+            entry.set_flags(entry.flags().foreign(true));
+            table.set_entry(i, entry);
+        }
+    }
+}
+
+// Call `f` with the address of every table below entries `entries` of `table`, until it fails
+unsafe fn for_each_table<A: Arch, E>(
+    table: &PageTable<A>,
+    entries: Range<usize>,
+    f: &mut impl FnMut(PhysicalAddress) -> Result<(), E>,
+) -> Result<(), E> {
+    unsafe {
+        for i in entries {
+            if !table.entry(i).is_some_and(|entry| entry.present()) {
+                continue;
+            }
+            if let Some(next) = table.next(i) {
+                f(next.phys())?;
+                for_each_table(&next, 0..A::PAGE_ENTRIES, f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Check if `flags` survive in a huge entry. On x86_64, the write combining bit of base pages is
+// the huge bit of directory entries, where selecting the same PAT entry has to use bit 12.
+fn huge_supports_flags<A: Arch>(flags: PageFlags<A>) -> bool {
+    A::ENTRY_FLAG_WRITE_COMBINING & A::ENTRY_FLAG_HUGE == 0
+        || flags.data() & A::ENTRY_FLAG_WRITE_COMBINING == 0
+}
+
+// Check if any entry of `table` is present or holds a swap token
+unsafe fn table_is_populated<A: Arch>(table: &PageTable<A>) -> bool {
+    unsafe {
+        (0..A::PAGE_ENTRIES)
+            .map(|j| table.entry(j).expect("must be within bounds"))
+            .any(|e| e.present() || entry_swap_token(e).is_some())
+    }
+}
+
+// Free the tables below `table` that overlap `range` and are not populated anymore. Top-level
+// entries of the kernel half are kept, as they may be shared.
+unsafe fn free_empty_tables<A: Arch>(
+    table: &mut PageTable<A>,
+    range: &VirtRange,
+    allocator: &mut impl FrameAllocator,
+) {
+    unsafe {
+        for i in 0..A::PAGE_ENTRIES {
+            let base = table.entry_base(i).expect("must be within bounds");
+            if !range.overlaps(&VirtRange::new(base, table.entry_size())) {
+                continue;
+            }
+            let Some(mut next) = table.next(i) else {
+                continue;
+            };
+            free_empty_tables(&mut next, range, allocator);
+            let shared = table.level() == A::PAGE_LEVELS - 1 && i >= A::PAGE_ENTRIES / 2;
+            if shared || table_is_populated(&next) {
+                continue;
+            }
+            if !table
+                .entry(i)
+                .is_some_and(|entry| entry.flags().has_foreign())
+            {
+                allocator.free_one(next.phys());
+            }
+            table.set_entry(i, PageEntry::new(0, 0));
+        }
+    }
+}
+
+unsafe fn unmap_phys_inner<A: Arch>(
+    virt: VirtualAddress,
+    table: &mut PageTable<A>,
+    initial_level: usize,
+    unmap_parents: bool,
+    allocator: &mut impl FrameAllocator,
+) -> Option<(PhysicalAddress, PageFlags<A>)> {
+    unsafe {
+        let i = table.index_of(virt)?;
+
+        if table.level() == 0 {
+            let entry_opt = table.entry(i);
+            table.set_entry(i, PageEntry::new(0, 0));
+            let entry = entry_opt?;
+
+            Some((entry.address().ok()?, entry.flags()))
+        } else {
+            let mut subtable = table.next(i)?;
+
+            let res =
+                unmap_phys_inner(virt, &mut subtable, initial_level, unmap_parents, allocator)?;
+
+            //TODO: This is a bad idea for architectures where the kernel mappings are done in the process tables,
+            // as these mappings may become out of sync
+            if unmap_parents {
+                // TODO: Use a counter? This would reduce the remaining number of available bits, but could be
+                // faster (benchmark is needed).
+                if !table_is_populated(&subtable) {
+                    if !table
+                        .entry(i)
+                        .is_some_and(|entry| entry.flags().has_foreign())
+                    {
+                        allocator.free_one(subtable.phys());
+                    }
+                    table.set_entry(i, PageEntry::new(0, 0));
+                }
+            }
+
+            Some(res)
+        }
+    }
+}
+impl<A, F: core::fmt::Debug> core::fmt::Debug for PageMapper<A, F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PageMapper")
+            .field("frame", &self.table_addr)
             .field("allocator", &self.allocator)
             .finish()
     }
 }
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+    use crate::{BuddyAllocator, EmulateArch, TinyAllocator, MEGABYTE};
+
+    type A = EmulateArch;
+
+    /// Mapper of the kernel table of a fresh machine, with a page in the kernel half and a frame
+    /// that nothing else uses
+    unsafe fn kernel_fixture() -> (
+        PageMapper<A, BuddyAllocator<A>>,
+        VirtualAddress,
+        PhysicalAddress,
+    ) {
+        unsafe {
+            (
+                PageMapper::current(TableKind::Kernel, A::init_test()),
+                VirtualAddress::new(A::PHYS_OFFSET + (1 << 30)),
+                PhysicalAddress::new(16 * MEGABYTE),
+            )
+        }
+    }
+
+    #[test]
+    fn af_tracked() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = mapper.allocator_mut().allocate_one().unwrap();
+
+            mapper
+                .map_af_tracked(virt, phys, PageFlags::new().write(true))
+                .unwrap()
+                .flush();
+            let (_, flags) = mapper.translate(virt).unwrap();
+            assert!(!flags.has_accessed());
+
+            let mut recorded = None;
+            mapper
+                .handle_af_fault(virt, |phys| recorded = Some(phys))
+                .unwrap()
+                .flush();
+            assert_eq!(recorded, Some(phys));
+            let (translated, flags) = mapper.translate(virt).unwrap();
+            assert_eq!(translated, phys);
+            assert!(flags.has_accessed());
+            assert!(flags.has_write());
+        }
+    }
+
+    #[test]
+    fn fault_set_dirty_writable() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = mapper.allocator_mut().allocate_one().unwrap();
+            mapper
+                .map_phys(virt, phys, PageFlags::new().clean_readonly())
+                .unwrap()
+                .flush();
+
+            mapper.fault_set_dirty_writable(virt).unwrap().flush();
+            let (translated, flags) = mapper.translate(virt).unwrap();
+            assert_eq!(translated, phys);
+            assert!(flags.has_write());
+            assert!(flags.has_dirty());
+            assert!(flags.has_accessed());
+
+            // A retry of the same fault, or another core faulting on the page, finds it handled
+            assert_eq!(
+                mapper.fault_set_dirty_writable(virt).err(),
+                Some(MapError::AlreadyHandled)
+            );
+            assert_eq!(mapper.translate(virt).unwrap().1.data(), flags.data());
+            assert_eq!(
+                mapper
+                    .fault_set_dirty_writable(virt.add(A::PAGE_SIZE))
+                    .err(),
+                Some(MapError::InvalidAddress)
+            );
+        }
+    }
+
+    #[test]
+    fn clean_readonly() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = mapper.allocator_mut().allocate_one().unwrap();
+
+            let flags = PageFlags::new().write(true).dirty(true).clean_readonly();
+            mapper.map_phys(virt, phys, flags).unwrap().flush();
+            let (translated, flags) = mapper.translate(virt).unwrap();
+            assert_eq!(translated, phys);
+            assert!(!flags.has_dirty());
+            assert!(!flags.has_write());
+            assert!(!flags.has_execute());
+            assert!(flags.has_accessed());
+            assert_eq!(A::read::<usize>(virt), 0);
+        }
+    }
+
+    #[test]
+    fn predirty() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let stack = VirtualAddress::new(MEGABYTE);
+            let normal = stack.add(A::PAGE_SIZE);
+            let flags = PageFlags::new().write(true);
+
+            mapper.map(stack, flags.predirty()).unwrap().flush();
+            mapper.map(normal, flags).unwrap().flush();
+            let (_, flags) = mapper.translate(stack).unwrap();
+            assert!(flags.has_dirty());
+            assert!(flags.has_accessed());
+            assert!(flags.has_write());
+            assert!(!mapper.translate(normal).unwrap().1.has_dirty());
+        }
+    }
+
+    #[test]
+    fn audit_translations() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let flushed = VirtualAddress::new(MEGABYTE);
+            let stale = flushed.add(A::PAGE_SIZE);
+            let physmap = A::phys_to_virt(PhysicalAddress::new(MEGABYTE + 0x123));
+            let flags = PageFlags::new().write(true);
+
+            mapper.map(flushed, flags).unwrap().flush();
+            let addrs = [flushed.add(0x10), stale, physmap];
+            assert_eq!(mapper.audit_translations(&addrs).count(), 0);
+
+            mapper.map(stale, flags).unwrap().ignore();
+            let (phys, _) = mapper.translate(stale).unwrap();
+            assert_eq!(
+                mapper.audit_translations(&addrs).collect::<Vec<_>>(),
+                vec![(stale, Some(phys), None)]
+            );
+        }
+    }
+
+    #[test]
+    fn contains_ip() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let code = VirtualAddress::new(MEGABYTE);
+            let ip = code.add(0x123);
+
+            assert!(!mapper.contains_ip(ip));
+            mapper
+                .map(code, PageFlags::new().execute(true))
+                .unwrap()
+                .flush();
+            assert!(mapper.contains_ip(ip));
+            assert!(!mapper.contains_ip(code.add(A::PAGE_SIZE)));
+
+            // The code of the test itself runs outside of the emulated machine
+            assert!(!mapper.contains_current_ip());
+        }
+    }
+
+    #[test]
+    fn user_half() {
+        unsafe {
+            // User frames come from a separate allocator, to tell them apart from tables
+            let mut allocator = A::init_test();
+            let mut frames = TinyAllocator::<A>::empty();
+            frames.add(
+                allocator.allocate(FrameCount::new(16)).unwrap(),
+                FrameCount::new(16),
+            );
+            let free = allocator.usage().free().data();
+            let frames_free = frames.usage().free().data();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let flags = PageFlags::new().write(true);
+            let kernel_virt = VirtualAddress::new(0xFFFF_FF80_0000_0000);
+            let user_virts = [
+                VirtualAddress::new(MEGABYTE),
+                VirtualAddress::new(MEGABYTE + A::PAGE_SIZE),
+                VirtualAddress::new(0x7FFF_FFFF_F000),
+            ];
+
+            mapper.map(kernel_virt, flags).unwrap().flush();
+            let kernel_pages = mapper.iter().count();
+            for virt in user_virts {
+                let phys = frames.allocate_one().unwrap();
+                mapper
+                    .map_phys(virt, phys, flags.user(true))
+                    .unwrap()
+                    .flush();
+            }
+
+            let user: Vec<_> = mapper.iter_user().collect();
+            assert_eq!(user.len(), user_virts.len());
+            for ((virt, phys, flags, size), expected) in user.into_iter().zip(user_virts) {
+                assert_eq!(virt, expected);
+                assert_eq!(mapper.translate(virt).unwrap().0, phys);
+                assert!(flags.has_user());
+                assert_eq!(size, A::PAGE_SIZE);
+            }
+            assert!(mapper.iter().any(|(virt, ..)| virt == kernel_virt));
+
+            // Swapped out pages are reported instead of freed
+            let (phys, _) = mapper.swap_out(user_virts[1], 7).unwrap();
+            frames.free_one(phys);
+            let mut swapped = Vec::new();
+            mapper
+                .unmap_user_all(&mut frames, |virt, token| swapped.push((virt, token)))
+                .flush();
+            assert_eq!(swapped, [(user_virts[1], 7)]);
+            assert_eq!(frames.usage().free().data(), frames_free);
+            assert_eq!(mapper.iter_user().count(), 0);
+            for virt in user_virts {
+                assert!(mapper.translate(virt).is_none());
+            }
+            assert_eq!(mapper.iter().count(), kernel_pages);
+            assert!(mapper.translate(kernel_virt).is_some());
+            // Only the kernel page and its tables are left allocated
+            assert_eq!(mapper.allocator().usage().free().data(), free - 4);
+        }
+    }
+
+    #[test]
+    fn free_subtree() {
+        unsafe {
+            // Mapped frames come from a separate allocator, to tell them apart from tables
+            let mut allocator = A::init_test();
+            let mut frames = TinyAllocator::<A>::empty();
+            frames.add(
+                allocator.allocate(FrameCount::new(1024)).unwrap(),
+                FrameCount::new(1024),
+            );
+            let free = allocator.usage().free().data();
+            let frames_free = frames.usage().free().data();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let flags = PageFlags::new().write(true);
+            let top_size = A::PAGE_SIZE << (A::PAGE_ENTRY_SHIFT * (A::PAGE_LEVELS - 1));
+            let base = VirtualAddress::new(3 * top_size);
+            let other = VirtualAddress::new(4 * top_size);
+
+            // 600 pages spanning two level 0 tables, and four more in separate level 1 tables
+            let mut virts: Vec<_> = (0..600).map(|i| base.add(i * A::PAGE_SIZE)).collect();
+            virts.extend((1..5).map(|i| base.add(i * A::PAGE_SIZE << (2 * A::PAGE_ENTRY_SHIFT))));
+            for &virt in &virts {
+                let phys = frames.allocate_one().unwrap();
+                mapper.map_phys(virt, phys, flags).unwrap().ignore();
+            }
+            mapper.map(other, flags).unwrap().flush();
+            let tables = free - mapper.allocator().usage().free().data() - 1 - 3;
+            assert_eq!(tables, 1 + 5 + 6);
+
+            mapper
+                .free_subtree(3, &mut frames, false, |_, _| {})
+                .unwrap()
+                .flush();
+            assert!(mapper.translate(base).is_none());
+            assert!(mapper.translate(other).is_some());
+            assert_eq!(mapper.allocator().usage().free().data(), free - 1 - 3);
+            assert_eq!(frames.usage().free().data(), frames_free - virts.len());
+
+            let phys = frames.allocate_one().unwrap();
+            mapper.map_phys(base, phys, flags).unwrap().flush();
+            mapper
+                .free_subtree(3, &mut frames, true, |_, _| {})
+                .unwrap()
+                .flush();
+            assert!(matches!(
+                mapper.free_subtree(A::PAGE_ENTRIES, &mut frames, true, |_, _| {}),
+                Err(MapError::InvalidAddress)
+            ));
+            assert_eq!(frames.usage().free().data(), frames_free - virts.len());
+            assert_eq!(mapper.allocator().usage().free().data(), free - 1 - 3);
+        }
+    }
+
+    #[test]
+    fn iter_ranges() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::Kernel, &mut allocator).unwrap();
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = PhysicalAddress::new(8 * MEGABYTE);
+            let flags = PageFlags::new().write(true);
+            mapper
+                .map_range(virt, phys, 64, flags, MapOptions::default(), ())
+                .unwrap();
+
+            let ranges: Vec<_> = mapper.iter_ranges().collect();
+            assert_eq!(ranges.len(), 1);
+            let (range, range_phys, range_flags) = ranges[0];
+            assert_eq!(range, VirtRange::new(virt, 64 * A::PAGE_SIZE));
+            assert_eq!(range_phys, phys);
+            assert_eq!(range_flags.data(), flags.data());
+
+            // Different flags, a physical gap and a virtual gap each start a new range
+            let end = virt.add(64 * A::PAGE_SIZE);
+            let end_phys = phys.add(64 * A::PAGE_SIZE);
+            mapper
+                .map_phys(end, end_phys, flags.user(true))
+                .unwrap()
+                .ignore();
+            let gap = end.add(A::PAGE_SIZE);
+            mapper.map_phys(gap, end_phys, flags).unwrap().ignore();
+            let after = gap.add(2 * A::PAGE_SIZE);
+            mapper
+                .map_phys(after, end_phys.add(A::PAGE_SIZE), flags)
+                .unwrap()
+                .ignore();
+            let bases: Vec<_> = mapper.iter_ranges().map(|(range, ..)| range.base).collect();
+            assert_eq!(bases, vec![virt, end, gap, after]);
+        }
+    }
+
+    #[test]
+    fn size_breakdown() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let flags = PageFlags::new().write(true);
+            let physmap = mapper.size_breakdown();
+            // The physmap of the emulated machine is made of base pages
+            assert_eq!(physmap.total(), physmap.by_level[0]);
+            assert_eq!(physmap.total(), mapper.iter().count() * A::PAGE_SIZE);
+
+            let gigabyte = 1024 * MEGABYTE;
+            let base = VirtualAddress::new(4 * gigabyte);
+            mapper
+                .map_huge(base, PhysicalAddress::new(gigabyte), 2, flags)
+                .unwrap()
+                .flush();
+            for i in 1..4 {
+                let virt = base.add(gigabyte + i * 2 * MEGABYTE);
+                let phys = PhysicalAddress::new(i * 2 * MEGABYTE);
+                mapper.map_huge(virt, phys, 1, flags).unwrap().flush();
+            }
+            for i in 0..5 {
+                mapper
+                    .map(base.add(2 * gigabyte + i * A::PAGE_SIZE), flags)
+                    .unwrap()
+                    .flush();
+            }
+
+            let breakdown = mapper.size_breakdown();
+            assert_eq!(
+                breakdown.by_level[0] - physmap.by_level[0],
+                5 * A::PAGE_SIZE
+            );
+            assert_eq!(breakdown.by_level[1], 3 * 2 * MEGABYTE);
+            assert_eq!(breakdown.by_level[2], gigabyte);
+            assert_eq!(breakdown.by_level[3], 0);
+            assert_eq!(
+                breakdown.total(),
+                mapper.iter().map(|(.., size)| size).sum::<usize>()
+            );
+        }
+    }
+
+    #[test]
+    fn share_kernel_half() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut tables = TinyAllocator::<A>::empty();
+            tables.add(
+                allocator.allocate(FrameCount::new(8)).unwrap(),
+                FrameCount::new(8),
+            );
+            let mut space = PageMapper::<A, _>::new_empty(TableKind::User, &mut tables).unwrap();
+            let mut kernel = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let flags = PageFlags::new().write(true);
+
+            space.share_kernel_half(&kernel);
+            let physmap = A::phys_to_virt(PhysicalAddress::new(MEGABYTE));
+            assert_eq!(
+                space.translate(physmap).unwrap().0,
+                PhysicalAddress::new(MEGABYTE)
+            );
+
+            // Mapped below an already shared top-level entry
+            let virt = VirtualAddress::new(A::PHYS_OFFSET + (1 << 30));
+            assert!(space.translate(virt).is_none());
+            kernel.map(virt, flags).unwrap().flush();
+            let (phys, _) = kernel.translate(virt).unwrap();
+            assert_eq!(space.translate(virt).unwrap().0, phys);
+
+            // The shared tables can not be freed through the address space
+            let top_index = A::PAGE_ENTRIES / 2;
+            assert!(matches!(
+                space.free_subtree(
+                    top_index,
+                    &mut TinyAllocator::<A>::empty(),
+                    false,
+                    |_, _| {}
+                ),
+                Err(MapError::Protected)
+            ));
+            assert_eq!(space.translate(virt).unwrap().0, phys);
+
+            // The user half is left alone
+            assert!(space.iter_user().next().is_none());
+            let top_size = A::PAGE_SIZE << (A::PAGE_ENTRY_SHIFT * (A::PAGE_LEVELS - 1));
+            assert!(space.translate(VirtualAddress::new(top_size)).is_none());
+        }
+    }
+
+    #[test]
+    fn check_half_isolation() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            let flags = PageFlags::new().write(true);
+            mapper
+                .map(VirtualAddress::new(MEGABYTE), flags.user(true))
+                .unwrap()
+                .ignore();
+            mapper
+                .map(VirtualAddress::new(A::PHYS_OFFSET), flags)
+                .unwrap()
+                .ignore();
+            let mut buffer = [PhysicalAddress::new(0); 8];
+            assert_eq!(mapper.check_half_isolation(&mut buffer), Ok(()));
+            assert_eq!(
+                mapper.check_half_isolation(&mut buffer[..2]),
+                Err(IsolationError::BufferTooSmall)
+            );
+
+            // A kernel entry pointing at the tables of the user half
+            let mut table = mapper.table();
+            let user = table.entry(0).unwrap();
+            table.set_entry(A::PAGE_ENTRIES - 1, user);
+            assert_eq!(
+                mapper.check_half_isolation(&mut buffer),
+                Err(IsolationError::Aliased(user.address().unwrap()))
+            );
+
+            // Aliasing a lower level table is found as well
+            table.set_entry(A::PAGE_ENTRIES - 1, PageEntry::new(0, 0));
+            assert_eq!(mapper.check_half_isolation(&mut buffer), Ok(()));
+            let kernel = table.next(A::PAGE_ENTRIES / 2).unwrap();
+            let mut user = table.next(0).unwrap();
+            user.set_entry(1, kernel.entry(0).unwrap());
+            assert_eq!(
+                mapper.check_half_isolation(&mut buffer),
+                Err(IsolationError::Aliased(
+                    kernel.entry(0).unwrap().address().unwrap()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn top_level_populated() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::Kernel, &mut allocator).unwrap();
+            assert!(mapper.top_level_populated().is_empty());
+
+            let top_size = A::PAGE_SIZE << (A::PAGE_ENTRY_SHIFT * (A::PAGE_LEVELS - 1));
+            let flags = PageFlags::new().write(true);
+            for i in [0, 3, 3, 255, 256, 511] {
+                let virt = VirtualAddress::new(i * top_size + MEGABYTE);
+                let virt = if i >= A::PAGE_ENTRIES / 2 {
+                    VirtualAddress::new(virt.data() | A::PAGE_NEGATIVE_MASK)
+                } else {
+                    virt
+                };
+                mapper.map(virt, flags).unwrap().ignore();
+            }
+
+            let populated = mapper.top_level_populated();
+            assert_eq!(populated.len(), 5);
+            assert_eq!(
+                populated.iter().collect::<Vec<_>>(),
+                vec![0, 3, 255, 256, 511]
+            );
+            assert!(!populated.contains(1));
+            assert!(!populated.contains(A::PAGE_ENTRIES));
+        }
+    }
+
+    #[test]
+    fn find_virt_for_phys() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = mapper.allocator_mut().allocate_one().unwrap();
+
+            // Only the physmap maps the frame so far
+            assert_eq!(mapper.find_virt_for_phys(phys), Some(A::phys_to_virt(phys)));
+            mapper
+                .map_phys(virt, phys, PageFlags::new())
+                .unwrap()
+                .flush();
+            assert_eq!(mapper.find_virt_for_phys(phys), Some(virt));
+            assert_eq!(
+                mapper.find_virt_for_phys(phys.add(0x123)),
+                Some(virt.add(0x123))
+            );
+            assert_eq!(
+                mapper.find_virt_for_phys(PhysicalAddress::new(1 << 30)),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn checksum() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let flags = PageFlags::new().write(true);
+
+            let before = mapper.checksum();
+            assert!(mapper.translate(virt).is_none());
+            assert_eq!(mapper.checksum(), before);
+
+            mapper.map(virt, flags).unwrap().flush();
+            let mapped = mapper.checksum();
+            assert_ne!(mapped, before);
+            assert!(mapper.translate(virt).is_some());
+            assert_eq!(mapper.checksum(), mapped);
+
+            // Only flags changing is still noticed
+            mapper.remap(virt, flags.write(false)).unwrap().flush();
+            assert_ne!(mapper.checksum(), mapped);
+        }
+    }
+
+    #[test]
+    fn unmap_range_splits_huge() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(1 << 30);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            let flags = PageFlags::new().write(true);
+            let page = |i: usize| i * A::PAGE_SIZE;
+            let used = mapper.allocator().usage().used().data();
+
+            assert_eq!(
+                mapper.map_huge(virt.add(page(1)), phys, 1, flags).err(),
+                Some(MapError::InvalidAddress)
+            );
+            mapper.map_huge(virt, phys, 1, flags).unwrap().flush();
+            assert_eq!(
+                mapper.try_map_phys(virt.add(page(1)), phys, flags).err(),
+                Some(MapError::InHugePage)
+            );
+            let (translated, huge_flags) = mapper.translate(virt.add(page(300))).unwrap();
+            assert_eq!(translated, phys.add(page(300)));
+            assert!(huge_flags.has_write());
+
+            let entries = 2 * MEGABYTE / A::PAGE_SIZE;
+            let middle = entries / 2;
+            mapper
+                .unmap_range(virt.add(page(middle)), 1, PageFlushAll::new(), |_, _| {})
+                .unwrap();
+            assert!(mapper.translate(virt.add(page(middle))).is_none());
+            for i in (0..entries).filter(|&i| i != middle) {
+                let (translated, flags) = mapper.translate(virt.add(page(i))).unwrap();
+                assert_eq!(translated, phys.add(page(i)));
+                assert!(flags.has_write());
+            }
+            assert_eq!(
+                A::read::<usize>(virt.add(page(middle + 1))),
+                A::read::<usize>(A::phys_to_virt(phys.add(page(middle + 1))))
+            );
+
+            // The block is now a table, so base pages can be mapped into the hole
+            mapper
+                .map_phys(virt.add(page(middle)), phys, flags)
+                .unwrap()
+                .flush();
+            assert_eq!(mapper.translate(virt.add(page(middle))).unwrap().0, phys);
+
+            // Even without the double map guard, a huge page never replaces the table
+            assert!(matches!(
+                mapper.map_huge(virt, phys, 1, flags),
+                Err(MapError::AlreadyMapped(_))
+            ));
+            assert_eq!(mapper.translate(virt.add(page(middle))).unwrap().0, phys);
+
+            // Unmapping everything frees the tables again
+            mapper
+                .unmap_range(virt, entries, PageFlushAll::new(), |_, _| {})
+                .unwrap();
+            assert!(mapper.translate(virt).is_none());
+            assert_eq!(mapper.allocator().usage().used().data(), used);
+        }
+    }
+
+    #[test]
+    fn map_range_huge() {
+        unsafe {
+            let virt = VirtualAddress::new((1 << 30) + 2 * MEGABYTE - 8 * A::PAGE_SIZE);
+            let phys = PhysicalAddress::new(16 * MEGABYTE - 8 * A::PAGE_SIZE);
+            let count = 8 + 2 * MEGABYTE / A::PAGE_SIZE + 8;
+            let flags = PageFlags::new().write(true);
+
+            for allow_huge in [false, true] {
+                let mut allocator = A::init_test();
+                let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+                mapper
+                    .map_range(
+                        virt,
+                        phys,
+                        count,
+                        flags,
+                        MapOptions { allow_huge },
+                        PageFlushAll::new(),
+                    )
+                    .unwrap();
+
+                for i in 0..count {
+                    let (translated, flags) = mapper.translate(virt.add(i * A::PAGE_SIZE)).unwrap();
+                    assert_eq!(translated, phys.add(i * A::PAGE_SIZE));
+                    assert!(flags.has_write());
+                }
+                assert!(mapper.translate(virt.add(count * A::PAGE_SIZE)).is_none());
+
+                let sizes: Vec<_> = mapper
+                    .iter()
+                    .filter(|&(page, ..)| page >= virt && page < virt.add(count * A::PAGE_SIZE))
+                    .map(|(.., size)| size)
+                    .collect();
+                if allow_huge {
+                    assert_eq!(
+                        sizes.iter().filter(|&&size| size == 2 * MEGABYTE).count(),
+                        1
+                    );
+                    assert_eq!(
+                        sizes.iter().filter(|&&size| size == A::PAGE_SIZE).count(),
+                        16
+                    );
+                } else {
+                    assert_eq!(sizes.len(), count);
+                    assert!(sizes.iter().all(|&size| size == A::PAGE_SIZE));
+                }
+            }
+
+            // Write combining huge pages would be write back, so base pages are used instead
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let flags = flags.write_combining(true);
+            let huge_virt = virt.add(8 * A::PAGE_SIZE);
+            let huge_phys = phys.add(8 * A::PAGE_SIZE);
+            assert!(matches!(
+                mapper.map_huge(huge_virt, huge_phys, 1, flags),
+                Err(MapError::InvalidFlags)
+            ));
+            mapper
+                .map_range(
+                    virt,
+                    phys,
+                    count,
+                    flags,
+                    MapOptions { allow_huge: true },
+                    PageFlushAll::new(),
+                )
+                .unwrap();
+            assert!(mapper.iter().all(|(.., size)| size == A::PAGE_SIZE));
+            let (_, translated) = mapper.translate(huge_virt).unwrap();
+            assert!(translated.has_flag(A::ENTRY_FLAG_WRITE_COMBINING));
+        }
+    }
+
+    #[test]
+    fn foreign() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let virt = VirtualAddress::new(MEGABYTE);
+            let flags = PageFlags::new().write(true).user(true);
+
+            let root = PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator)
+                .unwrap()
+                .table()
+                .phys();
+            let active = A::table(TableKind::User);
+            let phys = {
+                let mut foreign = PageMapper::<A, _>::new(TableKind::User, root, &mut allocator);
+                assert!(!foreign.is_current());
+                foreign.map(virt, flags).unwrap().ignore();
+                foreign.translate(virt).unwrap().0
+            };
+            A::write::<usize>(A::phys_to_virt(phys), 0x1234_5678);
+
+            // A second mapper over the same root sees the mapping, the active table does not
+            let foreign = PageMapper::<A, _>::new(TableKind::User, root, &mut allocator);
+            let (translated, translated_flags) = foreign.translate(virt).unwrap();
+            assert_eq!(translated, phys);
+            assert!(translated_flags.has_user());
+            assert_eq!(A::table(TableKind::User), active);
+            let current = PageMapper::<A, _>::current(TableKind::User, &mut allocator);
+            assert!(current.translate(virt).is_none());
+            assert!(current.translate(A::phys_to_virt(phys)).is_some());
+            assert_eq!(A::read::<usize>(A::phys_to_virt(phys)), 0x1234_5678);
+        }
+    }
+
+    #[test]
+    fn physmap() {
+        const GIGABYTE: usize = 1 << 30;
+        let base = PhysicalAddress::new(GIGABYTE - 2 * MEGABYTE - 2 * A::PAGE_SIZE);
+        let areas = [MemoryArea {
+            base,
+            size: 2 * A::PAGE_SIZE + 2 * MEGABYTE + GIGABYTE + 2 * MEGABYTE + A::PAGE_SIZE,
+        }];
+        let sizes = [
+            A::PAGE_SIZE,
+            A::PAGE_SIZE,
+            2 * MEGABYTE,
+            GIGABYTE,
+            2 * MEGABYTE,
+            A::PAGE_SIZE,
+        ];
+
+        let mut plan = [(PhysicalAddress::new(0), VirtualAddress::new(0), 0); 8];
+        assert_eq!(plan_physmap::<A>(&areas, &mut plan), sizes.len());
+        let mut phys = base;
+        for (&(page_phys, page_virt, page_size), size) in plan.iter().zip(sizes) {
+            assert_eq!(page_phys, phys);
+            assert_eq!(page_virt, unsafe { A::phys_to_virt(phys) });
+            assert_eq!(page_size, size);
+            phys = phys.add(size);
+        }
+        assert_eq!(phys, base.add(areas[0].size));
+
+        let mut short = [(PhysicalAddress::new(0), VirtualAddress::new(0), 0); 2];
+        assert_eq!(plan_physmap::<A>(&areas, &mut short), sizes.len());
+        assert_eq!(short, plan[..2]);
+
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            mapper
+                .map_physmap(&areas, PageFlags::new().write(true), ())
+                .unwrap();
+            for offset in [0, 3 * A::PAGE_SIZE, 5 * MEGABYTE, GIGABYTE + 3 * MEGABYTE] {
+                let phys = base.add(offset);
+                assert_eq!(
+                    mapper.translate(A::phys_to_virt(phys)).unwrap().0,
+                    PhysicalAddress::new(phys.data() & !A::PAGE_OFFSET_MASK)
+                );
+            }
+            assert!(mapper
+                .translate(A::phys_to_virt(base.add(areas[0].size)))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn default_nx() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator)
+                .with_default_nx(true);
+            assert!(mapper.default_nx());
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            // Present and writable, without the no-execute flag
+            let flags = PageFlags::from_raw(A::ENTRY_FLAG_PRESENT | A::ENTRY_FLAG_READWRITE);
+            assert!(flags.has_execute());
+
+            mapper.map_phys(virt, phys, flags).unwrap().flush();
+            let (_, mapped) = mapper.translate(virt).unwrap();
+            assert!(!mapped.has_execute());
+            assert!(mapped.has_write());
+
+            // An explicit request for execution is kept
+            let virt = virt.add(A::PAGE_SIZE);
+            mapper.map(virt, flags.execute(true)).unwrap().flush();
+            assert!(mapper.translate(virt).unwrap().1.has_execute());
+
+            let virt = VirtualAddress::new(1 << 30);
+            mapper.map_huge(virt, phys, 1, flags).unwrap().flush();
+            assert!(!mapper.translate(virt).unwrap().1.has_execute());
+
+            let virt = virt.add(2 * MEGABYTE);
+            mapper
+                .map_huge(virt, phys, 1, flags.execute(true))
+                .unwrap()
+                .flush();
+            assert!(mapper.translate(virt).unwrap().1.has_execute());
+        }
+    }
+
+    #[test]
+    fn table_walk_uncached() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let table_addr = A::table(TableKind::Kernel);
+            let mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator)
+                .with_table_walk_uncached(true);
+            assert!(mapper.table_walk_uncached());
+            assert!(A::table_walk_cached(TableKind::Kernel));
+
+            mapper.make_current();
+            assert!(!A::table_walk_cached(TableKind::Kernel));
+            assert!(A::table_walk_cached(TableKind::User));
+
+            let mapper = mapper.with_table_walk_uncached(false);
+            mapper.make_current();
+            assert!(A::table_walk_cached(TableKind::Kernel));
+            assert_eq!(A::table(TableKind::Kernel), table_addr);
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace() {
+        use crate::{RmmTrace, TraceEvent};
+        use std::sync::Mutex;
+
+        struct Recorder(Mutex<Vec<TraceEvent>>);
+        impl RmmTrace for Recorder {
+            fn event(&self, event: TraceEvent) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        unsafe {
+            let recorder: &'static Recorder = Box::leak(Box::new(Recorder(Mutex::new(Vec::new()))));
+            let mut allocator = A::init_test().with_trace(recorder);
+            let mut mapper =
+                PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator).with_trace(recorder);
+            let virt = VirtualAddress::new(MEGABYTE);
+
+            mapper
+                .map(virt, PageFlags::new().write(true))
+                .unwrap()
+                .flush();
+            let (phys, flags) = mapper.translate(virt).unwrap();
+            mapper.unmap(virt, false).unwrap().flush();
+
+            // The page is allocated first, then the three tables above it
+            let events = recorder.0.lock().unwrap();
+            assert_eq!(events.len(), 7);
+            assert_eq!(
+                events[0],
+                TraceEvent::Allocate {
+                    base: phys,
+                    count: 1
+                }
+            );
+            for event in &events[1..4] {
+                assert!(matches!(event, TraceEvent::Allocate { count: 1, .. }));
+            }
+            assert_eq!(
+                events[4..],
+                [
+                    TraceEvent::Map {
+                        virt,
+                        phys,
+                        flags: flags.data(),
+                        level: 0,
+                    },
+                    TraceEvent::Unmap { virt, phys },
+                    TraceEvent::Free {
+                        base: phys,
+                        count: 1
+                    },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn map_with_guards() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = PhysicalAddress::new(8 * MEGABYTE);
+            let flags = PageFlags::new().write(true);
+
+            let base = mapper
+                .map_with_guards(virt, phys, 3, flags, PageFlushAll::new())
+                .unwrap();
+            assert_eq!(base, virt.add(A::PAGE_SIZE));
+            assert!(mapper.translate(virt).is_none());
+            for i in 0..3 {
+                let page = base.add(i * A::PAGE_SIZE);
+                assert_eq!(
+                    mapper.translate(page).unwrap().0,
+                    phys.add(i * A::PAGE_SIZE)
+                );
+            }
+            assert!(mapper.translate(base.add(3 * A::PAGE_SIZE)).is_none());
+
+            // Neither guard may overlap a neighbouring mapping
+            let other = virt.add(16 * A::PAGE_SIZE);
+            for neighbour in [other, other.add(3 * A::PAGE_SIZE)] {
+                mapper.map_phys(neighbour, phys, flags).unwrap().flush();
+                assert!(matches!(
+                    mapper.map_with_guards(other, phys, 2, flags, PageFlushAll::new()),
+                    Err(MapError::AlreadyMapped(old)) if old == phys
+                ));
+                assert!(mapper.translate(other.add(A::PAGE_SIZE)).is_none());
+                mapper.unmap_phys(neighbour, false).unwrap().2.flush();
+            }
+        }
+    }
+
+    #[test]
+    fn relocate_kernel() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let old_base = VirtualAddress::new(0xFFFF_FFFF_8000_0000);
+            let new_base = VirtualAddress::new(0xFFFF_FFFF_9234_5000);
+            let text = PageFlags::new().execute(true);
+            let data = PageFlags::new().write(true).global(true);
+            let phys = PhysicalAddress::new(4 * MEGABYTE);
+
+            // Text in a huge page, followed by data in two base pages, a hole and a write combining
+            // page
+            mapper.map_huge(old_base, phys, 1, text).unwrap().flush();
+            let data_base = old_base.add(2 * MEGABYTE);
+            let data_virts = [data_base, data_base.add(2 * A::PAGE_SIZE)];
+            for virt in data_virts {
+                mapper.map(virt, data).unwrap().flush();
+            }
+            let wc_virt = data_base.add(3 * A::PAGE_SIZE);
+            mapper
+                .map(wc_virt, data.write_combining(true))
+                .unwrap()
+                .flush();
+            let size = 2 * MEGABYTE + 4 * A::PAGE_SIZE;
+            let old: Vec<_> = (0..size / A::PAGE_SIZE)
+                .map(|i| mapper.translate(old_base.add(i * A::PAGE_SIZE)))
+                .collect();
+
+            mapper.relocate_kernel(old_base, new_base, size).unwrap();
+            for (i, old) in old.into_iter().enumerate() {
+                let new = mapper.translate(new_base.add(i * A::PAGE_SIZE));
+                assert_eq!(new.map(|(phys, _)| phys), old.map(|(phys, _)| phys));
+                assert_eq!(
+                    new.map(|(_, flags)| flags.huge(false).data()),
+                    old.map(|(_, flags)| flags.huge(false).data())
+                );
+                assert!(mapper.translate(old_base.add(i * A::PAGE_SIZE)).is_none());
+            }
+            assert_eq!(
+                mapper.translate(new_base.add(0x1234)).unwrap().0,
+                phys.add(0x1000)
+            );
+            assert!(!mapper.is_huge_mapped(new_base, 1));
+            assert_eq!(A::read::<usize>(new_base.add(2 * MEGABYTE)), 0);
+            let (_, wc_flags) = mapper
+                .translate(new_base.add(2 * MEGABYTE + 3 * A::PAGE_SIZE))
+                .unwrap();
+            assert!(wc_flags.has_flag(A::ENTRY_FLAG_WRITE_COMBINING));
+
+            // The target must be free and apart from the source
+            assert!(matches!(
+                mapper.relocate_kernel(new_base, new_base.add(A::PAGE_SIZE), size),
+                Err(MapError::InvalidAddress)
+            ));
+            let occupied = old_base.add(A::PAGE_SIZE);
+            mapper.map(occupied, data).unwrap().flush();
+            assert!(matches!(
+                mapper.relocate_kernel(new_base, old_base, size),
+                Err(MapError::AlreadyMapped(_))
+            ));
+            assert!(mapper.translate(new_base).is_some());
+            assert!(mapper.translate(old_base).is_none());
+        }
+    }
+
+    #[test]
+    fn is_huge_mapped() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let huge = VirtualAddress::new(1 << 30);
+            let base = huge.add(2 * MEGABYTE);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            let flags = PageFlags::new().write(true);
+
+            mapper.map_huge(huge, phys, 1, flags).unwrap().flush();
+            mapper
+                .map_range(
+                    base,
+                    phys,
+                    2 * MEGABYTE / A::PAGE_SIZE,
+                    flags,
+                    MapOptions::default(),
+                    (),
+                )
+                .unwrap();
+
+            assert!(mapper.is_huge_mapped(huge, 1));
+            assert!(mapper.is_huge_mapped(huge.add(MEGABYTE + 0x123), 1));
+            assert!(!mapper.is_huge_mapped(huge, 0));
+            assert!(!mapper.is_huge_mapped(huge, 2));
+            assert!(!mapper.is_huge_mapped(base, 1));
+            assert!(!mapper.is_huge_mapped(base.add(2 * MEGABYTE), 1));
+        }
+    }
+
+    #[test]
+    fn new_empty() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let used = allocator.usage().used().data();
+            let mapper = PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            assert_eq!(mapper.allocator().usage().used().data(), used + 1);
+
+            let table = mapper.table();
+            assert_eq!(table.phys().data() & A::PAGE_OFFSET_MASK, 0);
+            for i in 0..A::PAGE_ENTRIES {
+                assert_eq!(table.entry(i).unwrap().data(), 0);
+            }
+            for address in [0, A::PAGE_SIZE, MEGABYTE, 0x7FFF_FFFF_F000, A::PHYS_OFFSET] {
+                assert!(mapper.translate(VirtualAddress::new(address)).is_none());
+            }
+
+            let mut exhausted = A::init_test();
+            while exhausted.allocate_one().is_some() {}
+            assert_eq!(
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut exhausted).err(),
+                Some(MapError::OutOfFrames)
+            );
+        }
+    }
+
+    #[test]
+    fn map_allocates_needed_tables() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            let used = mapper.allocator().usage().used().data();
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            let flags = PageFlags::new().write(true).user(true);
+
+            mapper.map_phys(virt, phys, flags).unwrap().ignore();
+            assert_eq!(
+                mapper.allocator().usage().used().data(),
+                used + A::PAGE_LEVELS - 1
+            );
+
+            // A page sharing all tables allocates none
+            mapper
+                .map_phys(virt.add(A::PAGE_SIZE), phys, flags)
+                .unwrap()
+                .ignore();
+            assert_eq!(
+                mapper.allocator().usage().used().data(),
+                used + A::PAGE_LEVELS - 1
+            );
+        }
+    }
+
+    #[test]
+    fn map_pattern() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            let pattern = [
+                Permissions::ReadWrite,
+                Permissions::ReadOnly,
+                Permissions::ReadExecute,
+                Permissions::None,
+            ];
+            mapper.map_pattern(virt, 10, &pattern, phys, ()).unwrap();
+
+            for i in 0..10 {
+                let page = virt.add(i * A::PAGE_SIZE);
+                let flags = mapper.translate_flags(page);
+                match pattern[i % pattern.len()] {
+                    Permissions::None => assert!(flags.is_none()),
+                    permissions => {
+                        let flags = flags.unwrap();
+                        assert_eq!(flags.has_write(), permissions == Permissions::ReadWrite);
+                        assert_eq!(flags.has_execute(), permissions == Permissions::ReadExecute);
+                        assert_eq!(
+                            mapper.translate(page).unwrap().0,
+                            phys.add(i * A::PAGE_SIZE)
+                        );
+                    }
+                }
+            }
+            assert!(mapper
+                .translate_flags(virt.add(10 * A::PAGE_SIZE))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn with_temp_map() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let base = VirtualAddress::new(MEGABYTE);
+            // Reserving only creates tables, nothing is mapped that would need flushing
+            let invalidations = A::invalidations();
+            let pool = mapper.install_scratch_region(base, 2).unwrap();
+            assert!(mapper.translate(base).is_none());
+            assert_eq!(A::invalidations(), invalidations);
+            let used = mapper.allocator().usage().used().data();
+
+            let a = mapper.allocator_mut().allocate_one().unwrap();
+            let b = mapper.allocator_mut().allocate_one().unwrap();
+            A::write(A::phys_to_virt(a), 0xAAAA_usize);
+            A::write(A::phys_to_virt(b), 0xBBBB_usize);
+            let flags = PageFlags::new().write(true);
+            mapper
+                .with_temp_map(&pool, a, flags, |mapper, outer| {
+                    mapper
+                        .with_temp_map(&pool, b, flags, |mapper, inner| {
+                            assert_ne!(outer, inner);
+                            assert_eq!(A::read::<usize>(outer), 0xAAAA);
+                            assert_eq!(A::read::<usize>(inner), 0xBBBB);
+                            assert_eq!(
+                                mapper.with_temp_map(&pool, a, flags, |_, _| ()).err(),
+                                Some(MapError::NoScratchSlot)
+                            );
+                        })
+                        .unwrap();
+                    assert_eq!(A::read::<usize>(outer), 0xAAAA);
+                })
+                .unwrap();
+
+            // Both slots are free again, and no tables were allocated for them
+            assert!(mapper.translate(base).is_none());
+            assert!(mapper.translate(base.add(A::PAGE_SIZE)).is_none());
+            assert_eq!(mapper.allocator().usage().used().data(), used + 2);
+            assert!(pool.claim().is_some() && pool.claim().is_some());
+
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            mapper.map_phys(base, phys, flags).unwrap().flush();
+            assert!(matches!(
+                mapper.install_scratch_region(base, 1),
+                Err(MapError::AlreadyMapped(old)) if old == phys
+            ));
+
+            // A mapped later slot is found before tables are created for the first one, which is
+            // in the gigabyte below
+            let other = VirtualAddress::new(1 << 30);
+            mapper
+                .map_phys(other.add(4 * A::PAGE_SIZE), phys, flags)
+                .unwrap()
+                .flush();
+            let used = mapper.allocator().usage().used().data();
+            let second = VirtualAddress::new(other.data() - A::PAGE_SIZE);
+            assert!(matches!(
+                mapper.install_scratch_region(second, 6),
+                Err(MapError::AlreadyMapped(old)) if old == phys
+            ));
+            assert_eq!(mapper.allocator().usage().used().data(), used);
+        }
+    }
+
+    #[test]
+    fn iter_ranges_bounded() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            for i in 0..5 {
+                mapper
+                    .map_phys(
+                        VirtualAddress::new((i + 1) * MEGABYTE),
+                        phys,
+                        PageFlags::new(),
+                    )
+                    .unwrap()
+                    .ignore();
+            }
+
+            let mut ranges = Vec::new();
+            let truncated = mapper.iter_ranges_bounded(3, |range, _, _| {
+                ranges.push(range);
+                ControlFlow::Continue(())
+            });
+            assert!(truncated);
+            assert_eq!(
+                ranges,
+                (1..4)
+                    .map(|i| VirtRange::new(VirtualAddress::new(i * MEGABYTE), A::PAGE_SIZE))
+                    .collect::<Vec<_>>()
+            );
+
+            let mut count = 0;
+            assert!(!mapper.iter_ranges_bounded(5, |_, _, _| {
+                count += 1;
+                ControlFlow::Continue(())
+            }));
+            assert_eq!(count, 5);
+            assert!(!mapper.iter_ranges_bounded(10, |_, _, _| ControlFlow::Continue(())));
+            assert!(mapper.iter_ranges_bounded(10, |_, _, _| ControlFlow::Break(())));
+        }
+    }
+
+    #[test]
+    fn write_protect_all_user() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+            let user = PageFlags::new().write(true).user(true);
+            let pages = [
+                (VirtualAddress::new(MEGABYTE), user),
+                (
+                    VirtualAddress::new(MEGABYTE + A::PAGE_SIZE),
+                    user.write(false),
+                ),
+                (
+                    VirtualAddress::new(MEGABYTE + 2 * A::PAGE_SIZE),
+                    user.execute(true),
+                ),
+                // Writable, but not a user page
+                (
+                    VirtualAddress::new(3 * MEGABYTE),
+                    PageFlags::new().write(true),
+                ),
+            ];
+            for (virt, flags) in pages {
+                mapper.map_phys(virt, phys, flags).unwrap().ignore();
+            }
+            let huge = VirtualAddress::new(1 << 30);
+            mapper.map_huge(huge, phys, 1, user).unwrap().ignore();
+            let physmap = A::phys_to_virt(phys);
+            let physmap_flags = mapper.translate_flags(physmap).unwrap();
+
+            mapper.write_protect_all_user().flush();
+            for (virt, flags) in pages {
+                let protected = mapper.translate_flags(virt).unwrap();
+                assert_eq!(
+                    protected.has_write(),
+                    !flags.has_user() && flags.has_write()
+                );
+                assert_eq!(protected.has_execute(), flags.has_execute());
+                assert_eq!(mapper.translate(virt).unwrap().0, phys);
+            }
+            assert!(mapper.is_huge_mapped(huge, 1));
+            assert!(!mapper.translate_flags(huge).unwrap().has_write());
+            assert_eq!(
+                mapper.translate_flags(physmap).unwrap().data(),
+                physmap_flags.data()
+            );
+        }
+    }
+
+    #[test]
+    fn adopt() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let flags = PageFlags::new().write(true).user(true);
+            let foreign_virt = VirtualAddress::new(MEGABYTE);
+            let owned_virt = VirtualAddress::new(1 << 30);
+            let foreign_phys = allocator.allocate_one().unwrap();
+            let owned_phys = allocator.allocate_one().unwrap();
+
+            let mut builder =
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            builder
+                .map_phys(foreign_virt, foreign_phys, flags)
+                .unwrap()
+                .ignore();
+            let root = builder.table().phys();
+            let foreign_table = builder.walk(foreign_virt).last().unwrap().table;
+            drop(builder);
+
+            let mut mapper = PageMapper::<A, _>::adopt(TableKind::User, root, &mut allocator);
+            assert!(mapper.translate_flags(foreign_virt).unwrap().has_foreign());
+            mapper
+                .map_phys(owned_virt, owned_phys, flags)
+                .unwrap()
+                .ignore();
+            assert!(!mapper.translate_flags(owned_virt).unwrap().has_foreign());
+            let owned_table = mapper.walk(owned_virt).last().unwrap().table;
+
+            mapper.unmap(foreign_virt, true).unwrap().ignore();
+            mapper.unmap(owned_virt, true).unwrap().ignore();
+            assert!(mapper.translate(foreign_virt).is_none());
+            let allocator = mapper.allocator();
+            assert_eq!(allocator.is_allocated(foreign_table), Some(true));
+            assert_eq!(allocator.is_allocated(foreign_phys), Some(true));
+            assert_eq!(allocator.is_allocated(owned_table), Some(false));
+            assert_eq!(allocator.is_allocated(owned_phys), Some(false));
+        }
+    }
+
+    #[test]
+    fn flush_policy() {
+        unsafe {
+            let (mut mapper, virt, _) = kernel_fixture();
+            let flags = PageFlags::new().write(true);
+
+            let invalidations = A::invalidations();
+            assert!(mapper
+                .map_with_policy(virt, flags, FlushPolicy::Immediate)
+                .unwrap()
+                .is_none());
+            assert_eq!(A::invalidations(), invalidations + 1);
+            assert!(A::hw_translate(virt).is_some());
+
+            let flush = mapper
+                .protect_with_policy(virt, flags.write(false), FlushPolicy::Deferred)
+                .unwrap()
+                .unwrap();
+            assert_eq!(A::invalidations(), invalidations + 1);
+            flush.flush();
+            assert_eq!(A::invalidations(), invalidations + 2);
+
+            // Without a flush, the stale translation stays in the TLB
+            assert!(mapper
+                .unmap_with_policy(virt, false, FlushPolicy::None)
+                .unwrap()
+                .is_none());
+            assert_eq!(A::invalidations(), invalidations + 2);
+            assert!(A::hw_translate(virt).is_some());
+            assert!(matches!(
+                mapper.unmap_with_policy(virt, false, FlushPolicy::None),
+                Err(MapError::InvalidAddress)
+            ));
+        }
+    }
+
+    #[test]
+    fn remap_break_before_make() {
+        use crate::arch::MachineEvent;
+
+        unsafe {
+            let (mut mapper, virt, phys) = kernel_fixture();
+            let flags = PageFlags::new().write(true);
+            let other = phys.add(A::PAGE_SIZE);
+            mapper.map_phys(virt, phys, flags).unwrap().flush();
+            A::set_break_before_make(true);
+
+            // Permission changes are written directly
+            A::record_events();
+            mapper.remap(virt, flags.write(false)).unwrap().flush();
+            let events = A::take_events();
+            assert!(matches!(events[0], MachineEvent::Write(_)));
+            assert_eq!(events[1..], [MachineEvent::Invalidate(virt)]);
+
+            // A new address is only written after the entry was cleared and invalidated
+            A::record_events();
+            let (_, _, flush) = mapper
+                .remap_with_full(virt, |_, flags| (other, flags))
+                .unwrap();
+            flush.flush();
+            let events = A::take_events();
+            let MachineEvent::Write(entry) = events[0] else {
+                panic!("expected a write first, got {:?}", events);
+            };
+            assert_eq!(
+                events,
+                [
+                    MachineEvent::Write(entry),
+                    MachineEvent::Invalidate(virt),
+                    MachineEvent::Write(entry),
+                    MachineEvent::Invalidate(virt),
+                ]
+            );
+            assert_eq!(mapper.translate(virt).unwrap().0, other);
+        }
+    }
+
+    #[test]
+    fn double_map_guard() {
+        unsafe {
+            let (mapper, virt, phys) = kernel_fixture();
+            let mut mapper = mapper.with_double_map_guard(true);
+            let flags = PageFlags::new().write(true);
+            let other = phys.add(A::PAGE_SIZE);
+            mapper.map_phys(virt, phys, flags).unwrap().flush();
+
+            assert_eq!(
+                mapper.try_map_phys(virt, other, flags).err(),
+                Some(MapError::AlreadyMapped(phys))
+            );
+            assert_eq!(mapper.translate(virt).unwrap().0, phys);
+            assert!(matches!(
+                mapper.try_map(virt, flags),
+                Err(MapError::AlreadyMapped(_))
+            ));
+
+            // A huge page over the table holding the page
+            let huge = PhysicalAddress::new(32 * MEGABYTE);
+            assert!(matches!(
+                mapper.map_huge(virt, huge, 1, flags),
+                Err(MapError::AlreadyMapped(_))
+            ));
+            assert_eq!(mapper.translate(virt).unwrap().0, phys);
+
+            let (_, _, flush) = mapper
+                .remap_with_full(virt, |_, flags| (other, flags))
+                .unwrap();
+            flush.flush();
+            assert_eq!(mapper.translate(virt).unwrap().0, other);
+        }
+    }
+
+    #[test]
+    fn table_overhead() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper =
+                PageMapper::<A, _>::new_empty(TableKind::User, &mut allocator).unwrap();
+            let flags = PageFlags::new().write(true).user(true);
+            let top = A::PAGE_SIZE << ((A::PAGE_LEVELS - 1) * A::PAGE_ENTRY_SHIFT);
+
+            let mut tables = |virt: usize, phys: usize, size: usize, allow_huge: bool| {
+                let used = mapper.allocator().usage().used().data();
+                let virt = VirtualAddress::new(virt);
+                let count = size / A::PAGE_SIZE;
+                mapper
+                    .map_range(
+                        virt,
+                        PhysicalAddress::new(phys),
+                        count,
+                        flags,
+                        MapOptions { allow_huge },
+                        (),
+                    )
+                    .unwrap();
+                let tables = mapper.allocator().usage().used().data() - used;
+                // The frames were never allocated, so only the tables are freed
+                for i in 0..2 {
+                    mapper
+                        .free_subtree(i, &mut TinyAllocator::<A>::empty(), false, |_, _| {})
+                        .unwrap()
+                        .ignore();
+                }
+                tables
+            };
+
+            let size = 5 * MEGABYTE + 3 * A::PAGE_SIZE;
+            let (best, worst) = super::table_overhead::<A>(size, false);
+            assert_eq!(tables(1 << 30, 0, size, false), best);
+            assert_eq!(tables(top - A::PAGE_SIZE, 0, size, false), worst);
+            assert!(best < worst);
+
+            let size = (2 << 30) + 6 * MEGABYTE + 2 * A::PAGE_SIZE;
+            let (best, worst) = super::table_overhead::<A>(size, true);
+            assert_eq!(best, A::PAGE_LEVELS - 1);
+            assert_eq!(tables(1 << 30, 4 << 30, size, true), best);
+            assert_eq!(
+                tables(top - A::PAGE_SIZE, (2 << 30) - A::PAGE_SIZE, size, true),
+                worst
+            );
+            assert_eq!(super::table_overhead::<A>(0, true), (0, 0));
+        }
+    }
+
+    #[test]
+    fn access_tracking() {
+        unsafe {
+            let (mut mapper, virt, phys) = kernel_fixture();
+            let flags = PageFlags::new().write(true).predirty();
+
+            assert!(A::ad_bits_hardware_managed());
+            let tracking = AccessTracking::current::<A>();
+            assert_eq!(tracking, AccessTracking::Scan);
+            mapper
+                .map_tracked(virt, phys, flags, tracking)
+                .unwrap()
+                .flush();
+            let mapped = mapper.translate_flags(virt).unwrap();
+            assert!(mapped.has_write() && !mapped.has_accessed() && !mapped.has_dirty());
+            mapper.unmap_phys(virt, false).unwrap().2.flush();
+
+            A::set_ad_bits_hardware_managed(false);
+            let tracking = AccessTracking::current::<A>();
+            assert_eq!(tracking, AccessTracking::Fault);
+            mapper
+                .map_tracked(virt, phys, flags, tracking)
+                .unwrap()
+                .flush();
+            let mapped = mapper.translate_flags(virt).unwrap();
+            assert!(!mapped.has_write() && !mapped.has_accessed() && !mapped.has_dirty());
+
+            // The first write is caught by the fault handler
+            mapper.fault_set_dirty_writable(virt).unwrap().flush();
+            let mapped = mapper.translate_flags(virt).unwrap();
+            assert!(mapped.has_write() && mapped.has_dirty());
+        }
+    }
+
+    #[test]
+    fn swap_out() {
+        unsafe {
+            let (mut mapper, virt, phys) = kernel_fixture();
+            let other = virt.add(A::PAGE_SIZE);
+            let flags = PageFlags::new().write(true);
+            mapper.map_phys(virt, phys, flags).unwrap().flush();
+            mapper
+                .map_phys(other, phys.add(A::PAGE_SIZE), flags.predirty())
+                .unwrap()
+                .flush();
+            assert_eq!(mapper.swap_token(virt), None);
+
+            assert_eq!(mapper.swap_out(virt, 0x1234), Ok((phys, false)));
+            assert!(mapper.translate(virt).is_none());
+            assert!(A::hw_translate(virt).is_none());
+            assert_eq!(mapper.swap_token(virt), Some(0x1234));
+            assert_eq!(mapper.swap_out(virt, 0x1234), Err(MapError::InvalidAddress));
+
+            // Token 0 is still told apart from an empty entry, and survives freeing of emptied
+            // tables
+            assert_eq!(
+                mapper.swap_out(other, 0),
+                Ok((phys.add(A::PAGE_SIZE), true))
+            );
+            assert_eq!(mapper.swap_token(other), Some(0));
+            let third = other.add(A::PAGE_SIZE);
+            mapper.map_phys(third, phys, flags).unwrap().flush();
+            mapper.unmap_phys(third, true).unwrap().2.flush();
+            assert_eq!(mapper.swap_token(virt), Some(0x1234));
+            assert_eq!(mapper.swap_token(other), Some(0));
+            assert_eq!(
+                mapper.swap_out(other, u64::MAX),
+                Err(MapError::SwapTokenTooLarge)
+            );
+
+            // Mapping the page again replaces the token
+            mapper.map_phys(virt, phys, flags).unwrap().flush();
+            assert_eq!(mapper.swap_token(virt), None);
+            assert_eq!(mapper.translate(virt).unwrap().0, phys);
+
+            // Non-present entries that keep their permissions are not tokens
+            let max = (1 << SWAP_TOKEN_BITS) - 1;
+            mapper.swap_out(virt, max).unwrap();
+            assert_eq!(mapper.swap_token(virt), Some(max));
+            mapper
+                .visit(third, |p1, i| {
+                    p1.set_entry(
+                        i,
+                        PageEntry::new(phys.data(), flags.data() & !A::ENTRY_FLAG_PRESENT),
+                    )
+                })
+                .unwrap();
+            assert_eq!(mapper.swap_token(third), None);
+
+            // Unmapping removes and reports the tokens
+            let mut swapped = Vec::new();
+            mapper
+                .unmap_range(virt, 3, PageFlushAll::new(), |virt, token| {
+                    swapped.push((virt, token))
+                })
+                .unwrap();
+            assert_eq!(swapped, [(virt, max), (other, 0)]);
+            assert_eq!(mapper.swap_token(virt), None);
+        }
+    }
+
+    #[test]
+    fn walk() {
+        unsafe {
+            let (mut mapper, base, _) = kernel_fixture();
+            let virt = base.add(0x1234_5000);
+            mapper.map(virt, PageFlags::new()).unwrap().ignore();
+
+            let steps: Vec<_> = mapper.walk(virt).collect();
+            assert_eq!(steps.len(), A::PAGE_LEVELS);
+            assert_eq!(steps[0].table, mapper.table().phys());
+            for (step, level) in steps.iter().zip((0..A::PAGE_LEVELS).rev()) {
+                assert_eq!(step.level, level);
+                assert_eq!(step.index, A::table_index(virt, level));
+            }
+            assert_eq!(steps[0].covers().size, A::PAGE_ADDRESS_SIZE as usize);
+            for step in &steps[1..] {
+                assert!(step.covers().contains(virt));
+            }
+
+            assert_eq!(
+                steps[1].covers(),
+                VirtRange::new(VirtualAddress::new(A::PHYS_OFFSET), 512 << 30)
+            );
+            // The table of 2 MiB entries, one level above the last, maps the surrounding 1 GiB
+            assert_eq!(steps[2].covers(), VirtRange::new(base, 1 << 30));
+            assert_eq!(steps[3].covers().base, base.add(0x1220_0000));
+            let (phys, _) = mapper.translate(virt).unwrap();
+            assert_eq!(steps[3].entry.address(), Ok(phys));
+
+            // Stops at the entry that is not present
+            let steps: Vec<_> = mapper.walk(VirtualAddress::new(1 << 40)).collect();
+            assert_eq!(steps.len(), 1);
+            assert!(!steps[0].entry.present());
+        }
+    }
+
+    #[test]
+    fn protected() {
+        static PROTECTED: [VirtRange; 1] = [VirtRange::new(
+            VirtualAddress::new(2 * MEGABYTE),
+            2 * MEGABYTE,
+        )];
+
+        unsafe {
+            let mut allocator = A::init_test();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator)
+                .with_protected(&PROTECTED);
+            let flags = PageFlags::new().write(true);
+            let phys = PhysicalAddress::new(16 * MEGABYTE);
+
+            let virt = VirtualAddress::new(2 * MEGABYTE + A::PAGE_SIZE);
+            assert_eq!(mapper.try_map(virt, flags).err(), Some(MapError::Protected));
+            assert_eq!(
+                mapper.try_map_phys(virt, phys, flags).err(),
+                Some(MapError::Protected)
+            );
+            assert!(mapper.translate(virt).is_none());
+
+            // Straddling the start of the protected range maps nothing
+            let virt = VirtualAddress::new(2 * MEGABYTE - 2 * A::PAGE_SIZE);
+            assert_eq!(
+                mapper.map_range(virt, phys, 4, flags, MapOptions::default(), ()),
+                Err(MapError::Protected)
+            );
+            assert!(mapper.translate(virt).is_none());
+
+            // Directly below and above is fine
+            mapper
+                .map_range(virt, phys, 2, flags, MapOptions::default(), ())
+                .unwrap();
+            assert_eq!(mapper.translate(virt).unwrap().0, phys);
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            mapper.map_phys(virt, phys, flags).unwrap().ignore();
+            assert_eq!(mapper.translate(virt).unwrap().0, phys);
+        }
+    }
+
+    #[test]
+    fn alloc_map_sg() {
+        unsafe {
+            let mut allocator = A::init_test();
+            let free = allocator.usage().free().data();
+            let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+            let virt = VirtualAddress::new(MEGABYTE);
+            let flags = PageFlags::new().write(true);
+
+            // Not enough room for the segments, everything is rolled back
+            let mut sg = [(PhysicalAddress::new(0), 0); 1];
+            assert_eq!(
+                mapper.alloc_map_sg(virt, 8, flags, &mut sg),
+                Err(MapError::TooManySegments)
+            );
+            for i in 0..8 {
+                assert!(mapper.translate(virt.add(i * A::PAGE_SIZE)).is_none());
+            }
+            assert_eq!(mapper.allocator().usage().free().data(), free);
+
+            let mut sg = [(PhysicalAddress::new(0), 0); 8];
+            let segments = mapper.alloc_map_sg(virt, 8, flags, &mut sg).unwrap();
+            assert!(segments > 1, "tables should split the frames");
+            assert_eq!(
+                sg[..segments].iter().map(|(_, size)| size).sum::<usize>(),
+                8 * A::PAGE_SIZE
+            );
+            let mut page = 0;
+            for &(base, size) in &sg[..segments] {
+                for offset in (0..size).step_by(A::PAGE_SIZE) {
+                    let (phys, _) = mapper.translate(virt.add(page * A::PAGE_SIZE)).unwrap();
+                    assert_eq!(phys, base.add(offset));
+                    page += 1;
+                }
+            }
+            for window in sg[..segments].windows(2) {
+                assert_ne!(window[0].0.add(window[0].1), window[1].0);
+            }
+        }
+    }
+}
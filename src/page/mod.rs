@@ -1,7 +1,8 @@
-pub use self::{entry::*, flags::*, flush::*, mapper::*, table::*};
+pub use self::{entry::*, flags::*, flush::*, iter::*, mapper::*, table::*};
 
 mod entry;
 mod flags;
 mod flush;
+mod iter;
 mod mapper;
 mod table;
@@ -57,6 +57,11 @@ impl<A: Arch> PageTable<A> {
         }
     }
 
+    /// Size of the memory mapped by one entry of this table
+    pub fn entry_size(&self) -> usize {
+        1 << (self.level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT)
+    }
+
     pub fn entry_base(&self, i: usize) -> Option<VirtualAddress> {
         if i < A::PAGE_ENTRIES {
             let level_shift = self.level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
@@ -91,6 +96,24 @@ impl<A: Arch> PageTable<A> {
         }
     }
 
+    /// Atomically replace entry `i` with `new` if it is still `current`, returning the entry
+    /// found instead on failure
+    pub unsafe fn compare_set_entry(
+        &mut self,
+        i: usize,
+        current: PageEntry<A>,
+        new: PageEntry<A>,
+    ) -> Option<Result<(), PageEntry<A>>> {
+        unsafe {
+            let addr = self.entry_virt(i)?;
+            Some(
+                A::compare_exchange(addr, current.data(), new.data())
+                    .map(|_| ())
+                    .map_err(PageEntry::from_data),
+            )
+        }
+    }
+
     pub unsafe fn index_of(&self, address: VirtualAddress) -> Option<usize> {
         // Canonicalize address first
         let address = VirtualAddress::new(address.data() & A::PAGE_ADDRESS_MASK);
@@ -101,7 +124,7 @@ impl<A: Arch> PageTable<A> {
             .wrapping_shl(level_shift as u32)
             .wrapping_sub(1);
         if address >= self.base && address <= self.base.add(level_mask) {
-            Some((address.data() >> level_shift) & A::PAGE_ENTRY_MASK)
+            Some(A::table_index(address, self.level))
         } else {
             None
         }
@@ -113,9 +136,14 @@ impl<A: Arch> PageTable<A> {
                 return None;
             }
 
+            let entry = self.entry(i)?;
+            if A::entry_is_huge(entry.data(), self.level) {
+                return None;
+            }
+
             Some(PageTable::new(
                 self.entry_base(i)?,
-                self.entry(i)?.address().ok()?,
+                entry.address().ok()?,
                 self.level - 1,
             ))
         }
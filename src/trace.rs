@@ -0,0 +1,28 @@
+use crate::{PhysicalAddress, VirtualAddress};
+
+/// Operation reported to an [`RmmTrace`] sink
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceEvent {
+    /// `count` frames were allocated at `base`
+    Allocate { base: PhysicalAddress, count: usize },
+    /// `count` frames at `base` were freed
+    Free { base: PhysicalAddress, count: usize },
+    /// `virt` was mapped to `phys` with the raw entry `flags`, in a table of `level`
+    Map {
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        flags: usize,
+        level: usize,
+    },
+    /// The page at `virt`, which mapped `phys`, was unmapped
+    Unmap {
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+    },
+}
+
+/// Sink for trace events of allocators and mappers, such as an early boot serial console. Only
+/// available with the `trace` feature.
+pub trait RmmTrace {
+    fn event(&self, event: TraceEvent);
+}